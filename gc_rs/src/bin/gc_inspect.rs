@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::process::ExitCode;
+
+use gc_rs::{HeapSnapshot, SnapshotObject};
+
+// Looks at snapshots written with `HeapSnapshot::save`, so dumps from test
+// runs can be picked apart without writing code.
+
+const USAGE: &str = "usage: gc-inspect <command> [args]
+
+commands:
+    summary <snapshot>              object, byte and root counts
+    top-types <snapshot> [n]        the n types retaining the most (default 10)
+    retainers <snapshot> <id>       what holds an object, and what it keeps alive
+    path-to-root <snapshot> <id>    a shortest chain of references from a root
+    diff <before> <after>           what grew and shrank between two snapshots
+    dot <snapshot> <id> [depth]     a Graphviz graph of what an object reaches
+    query <socket> <query>          ask a running inspection server for its
+                                    stats, census or snapshot";
+
+fn load(path: &str) -> Result<HeapSnapshot, String> {
+    HeapSnapshot::load(path).map_err(|err| format!("{}: {}", path, err))
+}
+
+fn parse<T: std::str::FromStr>(arg: &str, what: &str) -> Result<T, String> {
+    arg.parse().map_err(|_| format!("{} isn't a valid {}", arg, what))
+}
+
+fn object(snapshot: &HeapSnapshot, id: u64) -> Result<&SnapshotObject, String> {
+    snapshot.get(id).ok_or_else(|| format!("no object with id {}", id))
+}
+
+fn describe(object: &SnapshotObject) -> String {
+    match &object.alloc_site {
+        Some(site) => format!("{} {} @ {}", object.id, object.type_name, site),
+        None => format!("{} {}", object.id, object.type_name),
+    }
+}
+
+fn summary(snapshot: &HeapSnapshot) -> String {
+    let tree = snapshot.dominators();
+    let garbage: Vec<_> = snapshot.objects.iter().filter(|o| tree.retained_size(o.id).is_none()).collect();
+    let mut types: HashMap<&str, (usize, usize)> = HashMap::new();
+    for object in &snapshot.objects {
+        let entry = types.entry(&object.type_name).or_default();
+        entry.0 += 1;
+        entry.1 += object.size;
+    }
+    let mut types: Vec<_> = types.into_iter().collect();
+    types.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then(a.0.cmp(b.0)));
+
+    let mut out = format!(
+        "objects:     {}\nbytes:       {}\nrooted:      {}\nunreachable: {} objects, {} bytes\ntypes:       {}",
+        snapshot.objects.len(),
+        snapshot.total_bytes(),
+        snapshot.objects.iter().filter(|o| o.roots > 0).count(),
+        garbage.len(),
+        garbage.iter().map(|o| o.size).sum::<usize>(),
+        types.len(),
+    );
+    if !types.is_empty() {
+        out += "\n\nlargest types by shallow size:";
+        for (type_name, (count, bytes)) in types.iter().take(5) {
+            out += &format!("\n  {:>8} objects {:>10} bytes  {}", count, bytes, type_name);
+        }
+    }
+    out
+}
+
+fn top_types(snapshot: &HeapSnapshot, n: usize) -> String {
+    let tree = snapshot.dominators();
+    let mut shallow: HashMap<&str, usize> = HashMap::new();
+    for object in snapshot.objects.iter().filter(|o| tree.retained_size(o.id).is_some()) {
+        *shallow.entry(&object.type_name).or_default() += object.size;
+    }
+
+    let mut out = format!("{:>8} {:>10} {:>10}  type", "objects", "shallow", "retained");
+    for entry in tree.top_types(n) {
+        out += &format!(
+            "\n{:>8} {:>10} {:>10}  {}",
+            entry.count,
+            shallow.get(entry.type_name.as_str()).copied().unwrap_or(0),
+            entry.retained,
+            entry.type_name,
+        );
+    }
+    out
+}
+
+fn retainers(snapshot: &HeapSnapshot, id: u64) -> Result<String, String> {
+    let target = object(snapshot, id)?;
+    let tree = snapshot.dominators();
+
+    let mut out = describe(target);
+    out += &format!("\n  roots: {}\n  shallow: {} bytes", target.roots, target.size);
+    match tree.retained_size(id) {
+        Some(retained) => out += &format!("\n  retained: {} bytes", retained),
+        None => out += "\n  unreachable from the roots",
+    }
+
+    let referrers = snapshot.referrers(id);
+    if referrers.is_empty() {
+        out += "\n\nnot referenced by any object";
+    } else {
+        out += "\n\nreferenced by:";
+        for referrer in referrers {
+            out += &format!("\n  {}", describe(object(snapshot, referrer)?));
+        }
+    }
+    if tree.retained_size(id).is_some() {
+        match tree.immediate_dominator(id) {
+            Some(dominator) => out += &format!("\n\nkept alive by:\n  {}", describe(object(snapshot, dominator)?)),
+            None => out += "\n\nkept alive by the roots directly",
+        }
+    }
+    Ok(out)
+}
+
+fn path_to_root(snapshot: &HeapSnapshot, id: u64) -> Result<String, String> {
+    object(snapshot, id)?;
+    let path = snapshot.path_to_root(id).ok_or_else(|| format!("{} isn't reachable from the roots", id))?;
+    let mut out = String::new();
+    for (i, &step) in path.iter().enumerate() {
+        let step = describe(object(snapshot, step)?);
+        if i == 0 {
+            out += &format!("(root) {}", step);
+        } else {
+            out += &format!("\n  -> {}", step);
+        }
+    }
+    Ok(out)
+}
+
+// The answer comes once the heap's thread next allocates or reaches a
+// safepoint
+#[cfg(unix)]
+fn query(socket: &str, query: &str) -> Result<String, String> {
+    use std::io::Read;
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    let err = |err: io::Error| format!("{}: {}", socket, err);
+    let mut stream = UnixStream::connect(socket).map_err(err)?;
+    writeln!(stream, "{}", query).map_err(err)?;
+    stream.shutdown(Shutdown::Write).map_err(err)?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).map_err(err)?;
+    Ok(answer)
+}
+
+#[cfg(not(unix))]
+fn query(_socket: &str, _query: &str) -> Result<String, String> {
+    Err("the inspection server is only available on Unix".to_string())
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let out = match args.as_slice() {
+        ["summary", path] => summary(&load(path)?),
+        ["top-types", path] => top_types(&load(path)?, 10),
+        ["top-types", path, n] => top_types(&load(path)?, parse(n, "count")?),
+        ["retainers", path, id] => retainers(&load(path)?, parse(id, "object id")?)?,
+        ["path-to-root", path, id] => path_to_root(&load(path)?, parse(id, "object id")?)?,
+        ["diff", before, after] => load(before)?.diff(&load(after)?).to_string(),
+        ["dot", path, id, rest @ ..] if rest.len() <= 1 => {
+            let snapshot = load(path)?;
+            let id = parse(id, "object id")?;
+            object(&snapshot, id)?;
+            let depth = rest.first().map(|depth| parse(depth, "depth")).transpose()?;
+            let mut out = Vec::new();
+            snapshot.dump_dot(&mut out, id, depth).map_err(|err| err.to_string())?;
+            String::from_utf8(out).map_err(|err| err.to_string())?
+        }
+        ["query", socket, q] => query(socket, q)?,
+        _ => return Err(USAGE.to_string()),
+    };
+    writeln!(io::stdout(), "{}", out.trim_end()).map_err(|err| err.to_string())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) if err == USAGE => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+        Err(err) => {
+            eprintln!("gc-inspect: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::panic::Location;
+
+use crate::gc_state::*;
+
+/// The live objects of one type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TypeCensus {
+    pub count: usize,
+    /// Shallow bytes, as in `GcStats`
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CensusEntry {
+    pub type_name: &'static str,
+    /// Only set by `census_by_site`
+    pub alloc_site: Option<&'static Location<'static>>,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+pub(crate) type CensusTable = HashMap<&'static str, TypeCensus>;
+
+type SiteTable = HashMap<(&'static str, Option<&'static Location<'static>>), TypeCensus>;
+
+fn sorted(table: &CensusTable) -> Vec<CensusEntry> {
+    let mut entries: Vec<CensusEntry> = table
+        .iter()
+        .map(|(&type_name, c)| CensusEntry { type_name, alloc_site: None, count: c.count, bytes: c.bytes })
+        .collect();
+    entries.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.type_name.cmp(b.type_name)));
+    entries
+}
+
+fn sorted_by_site(table: &SiteTable) -> Vec<CensusEntry> {
+    let mut entries: Vec<CensusEntry> = table
+        .iter()
+        .map(|(&(type_name, alloc_site), c)| CensusEntry { type_name, alloc_site, count: c.count, bytes: c.bytes })
+        .collect();
+    entries.sort_by(|a, b| {
+        b.bytes
+            .cmp(&a.bytes)
+            .then(a.type_name.cmp(b.type_name))
+            .then(a.alloc_site.map(|l| (l.file(), l.line())).cmp(&b.alloc_site.map(|l| (l.file(), l.line()))))
+    });
+    entries
+}
+
+impl GcState {
+    /// Counts and bytes of live objects per type, largest first. With the
+    /// `stats` feature this reads a table kept up to date on allocation and
+    /// sweep; otherwise it walks the heap.
+    pub fn census(&self) -> Vec<CensusEntry> {
+        #[cfg(feature = "stats")]
+        return sorted(&self.census);
+
+        #[cfg(not(feature = "stats"))]
+        return self.walk_census();
+    }
+
+    /// Builds the census by walking every live node.
+    pub fn walk_census(&self) -> Vec<CensusEntry> {
+        let mut table = CensusTable::new();
+        for node in self.nodes() {
+            let entry = table.entry(node.type_info.name()).or_default();
+            entry.count += 1;
+            entry.bytes += std::mem::size_of_val(node);
+        }
+        sorted(&table)
+    }
+
+    /// Like `walk_census`, but split further by where the objects were
+    /// allocated. Without the `track-alloc-sites` feature there are no
+    /// sites, so this is the same as the plain census.
+    pub fn census_by_site(&self) -> Vec<CensusEntry> {
+        let mut table = SiteTable::new();
+        for node in self.nodes() {
+            let entry = table.entry((node.type_info.name(), node.alloc_site())).or_default();
+            entry.count += 1;
+            entry.bytes += std::mem::size_of_val(node);
+        }
+        sorted_by_site(&table)
+    }
+}
+
+#[cfg(feature = "stats")]
+pub(crate) fn census_add(table: &mut CensusTable, type_name: &'static str, size: usize) {
+    let entry = table.entry(type_name).or_default();
+    entry.count += 1;
+    entry.bytes += size;
+}
+
+#[cfg(feature = "stats")]
+pub(crate) fn census_sub(table: &mut CensusTable, type_name: &'static str, size: usize) {
+    if let Some(entry) = table.get_mut(type_name) {
+        entry.count -= 1;
+        entry.bytes -= size;
+        if entry.count == 0 {
+            table.remove(type_name);
+        }
+    }
+}
+
+pub fn census() -> Vec<CensusEntry> {
+    GC_STATE.with(|state| state.borrow().census())
+}
+
+pub fn census_by_site() -> Vec<CensusEntry> {
+    GC_STATE.with(|state| state.borrow().census_by_site())
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+
+use crate::snapshot::*;
+
+// The dominator tree of a snapshot, found with Lengauer-Tarjan (the simple
+// version, with path compression but no balancing). An object dominates
+// another if every path from the roots to the second goes through the
+// first, so freeing the dominator would free everything under it in the
+// tree: that's its retained size.
+//
+// The graph has a synthetic root, node 0, with an edge to every rooted
+// object. Object i in the snapshot is node i + 1.
+
+const NONE: usize = usize::MAX;
+
+pub struct DominatorTree<'a> {
+    snapshot: &'a HeapSnapshot,
+    index: HashMap<u64, usize>,
+    // Per node, NONE where unreachable. The synthetic root is its own idom.
+    idom: Vec<usize>,
+    retained: Vec<usize>,
+    children: Vec<Vec<usize>>,
+}
+
+/// How much one type keeps alive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeRetained {
+    pub type_name: String,
+    pub count: usize,
+    /// Bytes that would be freed if every object of the type was. Objects
+    /// under another of the same type are only counted once.
+    pub retained: usize,
+}
+
+impl HeapSnapshot {
+    /// Builds the dominator tree. Only the snapshot is used, so this can
+    /// run without holding up the heap.
+    pub fn dominators(&self) -> DominatorTree<'_> {
+        DominatorTree::new(self)
+    }
+}
+
+impl<'a> DominatorTree<'a> {
+    fn new(snapshot: &'a HeapSnapshot) -> Self {
+        let index: HashMap<u64, usize> = snapshot.objects.iter().enumerate().map(|(i, o)| (o.id, i)).collect();
+        let n = snapshot.objects.len() + 1;
+
+        let mut succs: Vec<Vec<usize>> = vec![Vec::new(); n];
+        for (i, object) in snapshot.objects.iter().enumerate() {
+            if object.roots > 0 {
+                succs[0].push(i + 1);
+            }
+            succs[i + 1].extend(object.edges.iter().filter_map(|id| index.get(id)).map(|&to| to + 1));
+        }
+        let mut preds: Vec<Vec<usize>> = vec![Vec::new(); n];
+        for (from, tos) in succs.iter().enumerate() {
+            for &to in tos {
+                preds[to].push(from);
+            }
+        }
+
+        // Number the nodes in depth first order
+        let mut dfnum = vec![NONE; n];
+        let mut vertex = Vec::with_capacity(n);
+        let mut parent = vec![NONE; n];
+        let mut stack = vec![(0, 0)];
+        dfnum[0] = 0;
+        vertex.push(0);
+        while let Some((v, next)) = stack.last_mut() {
+            let v = *v;
+            match succs[v].get(*next) {
+                Some(&w) => {
+                    *next += 1;
+                    if dfnum[w] == NONE {
+                        dfnum[w] = vertex.len();
+                        vertex.push(w);
+                        parent[w] = v;
+                        stack.push((w, 0));
+                    }
+                }
+                None => {
+                    stack.pop();
+                }
+            }
+        }
+
+        let mut semi = vec![NONE; n];
+        let mut idom = vec![NONE; n];
+        let mut samedom = vec![NONE; n];
+        let mut ancestor = vec![NONE; n];
+        let mut best: Vec<usize> = (0..n).collect();
+        let mut bucket: Vec<Vec<usize>> = vec![Vec::new(); n];
+
+        for i in (1..vertex.len()).rev() {
+            let node = vertex[i];
+            let p = parent[node];
+
+            // The semidominator: the earliest node with a path to this one
+            // through nodes only later than it
+            let mut s = p;
+            for &v in &preds[node] {
+                if dfnum[v] == NONE {
+                    continue;
+                }
+                let candidate = if dfnum[v] <= dfnum[node] {
+                    v
+                } else {
+                    semi[lowest_semi_ancestor(v, &mut ancestor, &mut best, &semi, &dfnum)]
+                };
+                if dfnum[candidate] < dfnum[s] {
+                    s = candidate;
+                }
+            }
+            semi[node] = s;
+            bucket[s].push(node);
+            ancestor[node] = p;
+
+            for v in std::mem::take(&mut bucket[p]) {
+                let y = lowest_semi_ancestor(v, &mut ancestor, &mut best, &semi, &dfnum);
+                if semi[y] == semi[v] {
+                    idom[v] = p;
+                } else {
+                    samedom[v] = y;
+                }
+            }
+        }
+        for &node in &vertex[1..] {
+            if samedom[node] != NONE {
+                idom[node] = idom[samedom[node]];
+            }
+        }
+        idom[0] = 0;
+
+        // Children come after their dominator in depth first order, so
+        // going backwards adds each to its dominator once it's complete
+        let mut retained = vec![0; n];
+        for (i, object) in snapshot.objects.iter().enumerate() {
+            retained[i + 1] = object.size;
+        }
+        let mut children = vec![Vec::new(); n];
+        for &node in vertex[1..].iter().rev() {
+            retained[idom[node]] += retained[node];
+            children[idom[node]].push(node);
+        }
+        for c in &mut children {
+            c.reverse();
+        }
+
+        DominatorTree { snapshot, index, idom, retained, children }
+    }
+
+    fn node(&self, id: u64) -> Option<usize> {
+        self.index.get(&id).map(|&i| i + 1).filter(|&node| self.idom[node] != NONE)
+    }
+
+    fn id(&self, node: usize) -> u64 {
+        self.snapshot.objects[node - 1].id
+    }
+
+    /// The object every path from the roots to `id` goes through, or
+    /// `None` if there isn't one (other than the roots themselves), or
+    /// `id` isn't reachable.
+    pub fn immediate_dominator(&self, id: u64) -> Option<u64> {
+        let idom = self.idom[self.node(id)?];
+        (idom != 0).then(|| self.id(idom))
+    }
+
+    /// The bytes that would be freed if `id` was, itself included. `None`
+    /// if it isn't reachable from the roots.
+    pub fn retained_size(&self, id: u64) -> Option<usize> {
+        Some(self.retained[self.node(id)?])
+    }
+
+    /// The objects `id` immediately dominates.
+    pub fn dominated(&self, id: u64) -> Vec<u64> {
+        match self.node(id) {
+            Some(node) => self.children[node].iter().map(|&c| self.id(c)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Objects immediately dominated by the roots, which together retain
+    /// everything reachable.
+    pub fn top_level(&self) -> Vec<u64> {
+        self.children[0].iter().map(|&c| self.id(c)).collect()
+    }
+
+    /// The `n` reachable objects with the largest retained sizes.
+    pub fn top_objects(&self, n: usize) -> Vec<u64> {
+        let mut nodes: Vec<usize> = (1..self.idom.len()).filter(|&node| self.idom[node] != NONE).collect();
+        nodes.sort_by(|&a, &b| self.retained[b].cmp(&self.retained[a]).then(a.cmp(&b)));
+        nodes.into_iter().take(n).map(|node| self.id(node)).collect()
+    }
+
+    /// The `n` types that retain the most.
+    pub fn top_types(&self, n: usize) -> Vec<TypeRetained> {
+        let mut types: HashMap<&str, TypeRetained> = HashMap::new();
+        // How many of each type are on the path from the root to the node
+        // being visited
+        let mut above: HashMap<&str, usize> = HashMap::new();
+        let mut stack = vec![(0, false)];
+        while let Some((node, leaving)) = stack.pop() {
+            let type_name = (node != 0).then(|| self.snapshot.objects[node - 1].type_name.as_str());
+            if leaving {
+                if let Some(type_name) = type_name {
+                    *above.get_mut(type_name).unwrap() -= 1;
+                }
+                continue;
+            }
+            if let Some(type_name) = type_name {
+                let entry = types.entry(type_name).or_insert_with(|| TypeRetained {
+                    type_name: type_name.to_string(),
+                    count: 0,
+                    retained: 0,
+                });
+                entry.count += 1;
+                let depth = above.entry(type_name).or_default();
+                if *depth == 0 {
+                    entry.retained += self.retained[node];
+                }
+                *depth += 1;
+            }
+            stack.push((node, true));
+            stack.extend(self.children[node].iter().map(|&c| (c, false)));
+        }
+
+        let mut types: Vec<TypeRetained> = types.into_values().collect();
+        types.sort_by(|a, b| b.retained.cmp(&a.retained).then(a.type_name.cmp(&b.type_name)));
+        types.truncate(n);
+        types
+    }
+}
+
+// The node with the lowest semidominator on the path up the linked forest
+// from `v`, compressing the path as it goes. Done with an explicit stack, as
+// paths can be as long as the heap is deep.
+fn lowest_semi_ancestor(
+    v: usize,
+    ancestor: &mut [usize],
+    best: &mut [usize],
+    semi: &[usize],
+    dfnum: &[usize],
+) -> usize {
+    let mut path = Vec::new();
+    let mut x = v;
+    while ancestor[x] != NONE && ancestor[ancestor[x]] != NONE {
+        path.push(x);
+        x = ancestor[x];
+    }
+    for &u in path.iter().rev() {
+        let a = ancestor[u];
+        if dfnum[semi[best[a]]] < dfnum[semi[best[u]]] {
+            best[u] = best[a];
+        }
+        ancestor[u] = ancestor[a];
+    }
+    best[v]
+}
@@ -0,0 +1,155 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+
+use crate::gc::Gc;
+use crate::gc_state::*;
+use crate::graph::*;
+use crate::snapshot::*;
+use crate::traits::*;
+
+/// Limits what `GcState::dump_dot_with` includes. By default every live
+/// object is dumped.
+#[derive(Debug, Clone, Default)]
+pub struct DotOptions {
+    start: Option<*const ()>,
+    max_depth: Option<usize>,
+}
+
+impl DotOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only dump objects reachable from `gc`.
+    pub fn reachable_from<T: Trace + ?Sized>(mut self, gc: &Gc<T>) -> Self {
+        self.start = Some(gc.node_addr());
+        self
+    }
+
+    /// Only dump objects at most `depth` edges away from the start (or from
+    /// a root, if no start was given).
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+}
+
+fn included(graph: &HeapGraph, options: &DotOptions) -> Vec<bool> {
+    if options.start.is_none() && options.max_depth.is_none() {
+        return vec![true; graph.nodes.len()];
+    }
+
+    let mut seen = vec![false; graph.nodes.len()];
+    let mut queue = VecDeque::new();
+    match options.start {
+        Some(addr) => queue.extend(graph.index.get(&addr).map(|&i| (i, 0))),
+        None => queue.extend(graph.roots().map(|i| (i, 0))),
+    }
+    for &(i, _) in &queue {
+        seen[i] = true;
+    }
+
+    while let Some((i, depth)) = queue.pop_front() {
+        if options.max_depth.is_some_and(|max| depth >= max) {
+            continue;
+        }
+        for &to in &graph.nodes[i].edges {
+            if !seen[to] {
+                seen[to] = true;
+                queue.push_back((to, depth + 1));
+            }
+        }
+    }
+    seen
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn write_node<W: Write>(w: &mut W, name: &str, type_name: &str, site: Option<&str>, roots: usize) -> io::Result<()> {
+    let site = match site {
+        Some(site) => format!("\\n{}", escape(site)),
+        None => String::new(),
+    };
+    writeln!(
+        w,
+        "    \"{}\" [label=\"{}{}\\nroots: {}\"{}];",
+        name,
+        escape(type_name),
+        site,
+        roots,
+        if roots > 0 { ", style=bold" } else { "" },
+    )
+}
+
+impl GcState {
+    /// Writes every live object and the `Gc` edges between them as a
+    /// Graphviz digraph. Rooted objects are drawn in bold.
+    pub fn dump_dot<W: Write>(&self, w: W) -> io::Result<()> {
+        self.dump_dot_with(w, &DotOptions::default())
+    }
+
+    pub fn dump_dot_with<W: Write>(&self, mut w: W, options: &DotOptions) -> io::Result<()> {
+        let graph = HeapGraph::new(self);
+        let included = included(&graph, options);
+
+        writeln!(w, "digraph heap {{")?;
+        writeln!(w, "    node [shape=box];")?;
+        for (node, _) in graph.nodes.iter().zip(&included).filter(|(_, &inc)| inc) {
+            let site = node.alloc_site.map(|site| site.to_string());
+            write_node(&mut w, &format!("{:p}", node.addr), node.type_name, site.as_deref(), node.roots)?;
+        }
+        for (node, _) in graph.nodes.iter().zip(&included).filter(|(_, &inc)| inc) {
+            for &to in node.edges.iter().filter(|&&to| included[to]) {
+                writeln!(w, "    \"{:p}\" -> \"{:p}\";", node.addr, graph.nodes[to].addr)?;
+            }
+        }
+        writeln!(w, "}}")
+    }
+}
+
+impl HeapSnapshot {
+    /// Writes the objects reachable from `start`, at most `max_depth` edges
+    /// away, as a Graphviz digraph like `GcState::dump_dot`. Nodes are
+    /// named by object id.
+    pub fn dump_dot<W: Write>(&self, mut w: W, start: u64, max_depth: Option<usize>) -> io::Result<()> {
+        let index: HashMap<u64, &SnapshotObject> = self.objects.iter().map(|o| (o.id, o)).collect();
+
+        let mut included = Vec::new();
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        if index.contains_key(&start) {
+            seen.insert(start);
+            queue.push_back((start, 0));
+        }
+        while let Some((id, depth)) = queue.pop_front() {
+            included.push(index[&id]);
+            if max_depth.is_some_and(|max| depth >= max) {
+                continue;
+            }
+            for &to in &index[&id].edges {
+                if index.contains_key(&to) && seen.insert(to) {
+                    queue.push_back((to, depth + 1));
+                }
+            }
+        }
+
+        writeln!(w, "digraph heap {{")?;
+        writeln!(w, "    node [shape=box];")?;
+        for object in &included {
+            write_node(&mut w, &object.id.to_string(), &object.type_name, object.alloc_site.as_deref(), object.roots)?;
+        }
+        for object in &included {
+            for to in object.edges.iter().filter(|to| seen.contains(to)) {
+                writeln!(w, "    \"{}\" -> \"{}\";", object.id, to)?;
+            }
+        }
+        writeln!(w, "}}")
+    }
+}
+
+/// Writes this thread's heap as a Graphviz digraph.
+pub fn dump_dot<W: Write>(w: W, options: &DotOptions) -> io::Result<()> {
+    GC_STATE.with(|state| state.borrow().dump_dot_with(w, options))
+}
@@ -1,10 +1,16 @@
 use crate::traits::*;
 use crate::gc_state::*;
-use std::cell::{Cell, RefCell};
+use crate::verify::{handle_derooted, handle_rooted};
+use std::any::TypeId;
+use std::cell::Cell;
+use std::mem::ManuallyDrop;
 use std::ptr::NonNull;
-use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
+#[cfg(feature = "nightly")]
+use std::marker::Unsize;
+#[cfg(feature = "nightly")]
+use std::ops::CoerceUnsized;
 
 
 pub struct Gc<T: Trace + ?Sized + 'static> {
@@ -16,7 +22,7 @@ pub struct Gc<T: Trace + ?Sized + 'static> {
 impl<T: Trace + ?Sized + 'static> Clone for Gc<T> {
     fn clone(&self) -> Self {
         let res = Gc {
-            gc_node_ptr: self.gc_node_ptr.clone(),
+            gc_node_ptr: self.gc_node_ptr,
             borrowed: self.borrowed.clone(),
             root: Cell::new(false),
         };
@@ -30,14 +36,74 @@ pub struct GcRefMut<T: Trace + ?Sized + 'static> {
     borrowed: Rc<Cell<bool>>,
 }
 
+// Lets a `Gc<T>` be used as a `Gc<dyn Trait>` where `T: Trait`, like a
+// `Box`. Without the `nightly` feature, `gc_dyn!` does the same.
+#[cfg(feature = "nightly")]
+impl<T, U> CoerceUnsized<Gc<U>> for Gc<T>
+where
+    T: Trace + ?Sized + Unsize<U> + 'static,
+    U: Trace + ?Sized + 'static,
+{
+}
+
+/// Turns a `Gc<T>` into a `Gc<dyn Trait>`, for any `Trait` that `T`
+/// implements and that has `Trace` as a supertrait. With the `nightly`
+/// feature this happens by coercion instead, as for `Box`.
+///
+/// ```
+/// use gc_rs::{gc_dyn, Gc, Trace};
+///
+/// trait Shape: Trace {
+///     fn area(&self) -> f64;
+/// }
+///
+/// #[derive(Trace)]
+/// struct Square(f64);
+///
+/// impl Shape for Square {
+///     fn area(&self) -> f64 {
+///         self.0 * self.0
+///     }
+/// }
+///
+/// let shapes: Vec<Gc<dyn Shape>> = vec![gc_dyn!(Gc::new(Square(2.0)), dyn Shape)];
+/// assert_eq!(shapes[0].area(), 4.0);
+/// ```
+///
+/// The handle is evaluated outside the macro's `unsafe` block, so unsafe
+/// calls in it still need their own:
+///
+/// ```compile_fail
+/// use gc_rs::{gc_dyn, Gc, Trace};
+///
+/// trait Shape: Trace {}
+///
+/// #[derive(Trace)]
+/// struct Square(f64);
+///
+/// impl Shape for Square {}
+///
+/// let g = Gc::new(Square(2.0));
+/// let shape: Gc<dyn Shape> = gc_dyn!({ g.deroot(); g }, dyn Shape);
+/// ```
+#[macro_export]
+macro_rules! gc_dyn {
+    ($gc:expr, $T:ty) => {{
+        let gc = $gc;
+        // SAFETY: the closure only coerces the pointer
+        unsafe { $crate::Gc::unsize_with::<$T>(gc, |ptr| ptr) }
+    }};
+}
+
 impl<T: Trace> Gc<T> {
+    #[cfg_attr(feature = "track-alloc-sites", track_caller)]
     pub fn new(value: T) -> Self {
         let val = GcNode::new(value);
         // Safety: Inaccessible elsewhere since it has just been created in the Gc
         unsafe {
-            let r = val.as_ref();
-            r.val.deroot_children();
+            deroot_children(&val.as_ref().val);
         }
+        handle_rooted(val.as_ptr() as *const ());
         Self {
             gc_node_ptr: val,
             borrowed: Rc::new(Cell::new(false)),
@@ -45,6 +111,11 @@ impl<T: Trace> Gc<T> {
         }
     }
 
+}
+
+impl<T: Trace + ?Sized + 'static> Gc<T> {
+    /// # Safety
+    /// The node must not have been freed by a `refresh`.
     pub unsafe fn get_roots(&self) -> usize {
         let r = self.gc_node_ptr.as_ref();
         r.data.get().get_roots()
@@ -58,13 +129,103 @@ impl<T: Trace> Gc<T> {
         Some(GcRefMut { gc_node_ptr: self.gc_node_ptr, borrowed: self.borrowed.clone() })
     }
 
+    /// Whether both handles point to the same object. Only the addresses
+    /// are compared, as a `dyn` handle's vtable can differ for the same
+    /// object.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.node_addr() == other.node_addr()
+    }
+
+    /// Turns the handle into one to an unsized type, as `gc_dyn!` does.
+    ///
+    /// # Safety
+    /// `unsize` must return the pointer it's given, coerced.
+    #[doc(hidden)]
+    pub unsafe fn unsize_with<U: Trace + ?Sized + 'static>(
+        self,
+        unsize: impl FnOnce(NonNull<GcNode<T>>) -> NonNull<GcNode<U>>,
+    ) -> Gc<U> {
+        let ptr = self.gc_node_ptr;
+        // SAFETY: the caller's
+        unsafe { self.with_node(unsize(ptr)) }
+    }
+
+    /// Whether the object was allocated as a `U`.
+    pub fn is<U: Trace + 'static>(&self) -> bool {
+        // SAFETY: the node outlives the handle
+        unsafe { self.gc_node_ptr.as_ref().type_info.id == TypeId::of::<U>() }
+    }
+
+    /// Turns a handle to a `dyn` type back into one to the type the object
+    /// was allocated as, if that's `U`. The new handle is rooted if this one
+    /// was. Otherwise this handle is given back.
+    pub fn downcast<U: Trace + 'static>(self) -> Result<Gc<U>, Self> {
+        if self.is::<U>() {
+            let ptr = self.gc_node_ptr.cast::<GcNode<U>>();
+            // SAFETY: the node was allocated as a `GcNode<U>`
+            Ok(unsafe { self.with_node(ptr) })
+        } else {
+            Err(self)
+        }
+    }
+
+    // Moves the handle, root and all, to another pointer to its node
+    //
+    // SAFETY: `ptr` must point to the same node, as a type it has
+    unsafe fn with_node<U: Trace + ?Sized + 'static>(self, ptr: NonNull<GcNode<U>>) -> Gc<U> {
+        // The root moves to the new handle, rather than being dropped
+        let this = ManuallyDrop::new(self);
+        Gc {
+            gc_node_ptr: ptr,
+            // SAFETY: `this` is never dropped, so this is a move
+            borrowed: unsafe { std::ptr::read(&this.borrowed) },
+            root: Cell::new(this.root.get()),
+        }
+    }
 
     pub fn is_root(&self) -> bool {
         self.root.get()
     }
 
-    pub fn ptr_eq(&self, other: &Self) -> bool {
-        self.gc_node_ptr == other.gc_node_ptr
+    /// Makes this handle keep its object alive. Handles start rooted, and
+    /// are derooted when moved into the heap with `Gc::new`.
+    pub fn root(&self) {
+        if !self.root.get() {
+            self.root.set(true);
+            handle_rooted(self.node_addr());
+            unsafe {
+                self.gc_node_ptr.as_ref().add_root();
+            }
+        }
+    }
+
+    /// Stops this handle keeping its object alive, leaving it to whatever
+    /// holds the handle. `Gc::new` does this for the handles in the value
+    /// it's given.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be inside an object in the heap, and reached by
+    /// tracing it, for as long as it's used. Otherwise nothing keeps the
+    /// object alive and the next collection can free it.
+    ///
+    /// ```compile_fail
+    /// let x = gc_rs::Gc::new(1);
+    /// x.deroot();
+    /// ```
+    pub unsafe fn deroot(&self) {
+        if self.root.get() {
+            self.root.set(false);
+            handle_derooted(self.node_addr());
+            unsafe {
+                self.gc_node_ptr.as_ref().sub_root();
+            }
+        }
+    }
+
+    // The address of the node, as used to identify it by the inspection tools
+    pub(crate) fn node_addr(&self) -> *const () {
+        self.gc_node_ptr.as_ptr() as *const ()
     }
 }
 
@@ -105,9 +266,10 @@ impl<T: Trace + ?Sized + 'static> Drop for GcRefMut<T> {
 impl<T: Trace + ?Sized + 'static> Drop for Gc<T> {
     fn drop(&mut self) {
         if self.root.get() {
+            handle_derooted(self.node_addr());
             unsafe {
-                let r = self.gc_node_ptr.as_mut();
-                r.data.get_mut().sub_roots();
+                let r = self.gc_node_ptr.as_ref();
+                r.sub_root();
                 if !r.data.get().is_root() {
                     self.root.set(false);
                 }
@@ -116,136 +278,150 @@ impl<T: Trace + ?Sized + 'static> Drop for Gc<T> {
     } 
 }
 
-impl<T: Trace + ?Sized + 'static> Trace for Gc<T> {
-    fn trace(&self) {
-        unsafe { 
-            let ptr = self.gc_node_ptr.as_ref();
-            let mut data = ptr.data.get();
-            if !data.is_marked() {
-                data.mark();
-                ptr.data.set(data);
-                ptr.val.trace();
-            }
-        }
+unsafe impl<T: Trace + ?Sized + 'static> Trace for Gc<T> {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        tracer.edge(GcEdge { handle: self, shared: false });
     }
+}
 
-    fn root_children(&self) {
-        unsafe { 
-            let ptr = self.gc_node_ptr.as_ref();
-            ptr.val.root_children();
-        }
+// What a `Tracer` can do with a handle, without knowing what it points to
+pub(crate) trait Handle {
+    fn node_addr(&self) -> *const ();
+    fn is_root(&self) -> bool;
+    fn root(&self);
+    fn deroot(&self);
+    fn data(&self) -> &Cell<GcData>;
+    fn trace_target(&self, tracer: &mut dyn Tracer);
+}
+
+impl<T: Trace + ?Sized + 'static> Handle for Gc<T> {
+    fn node_addr(&self) -> *const () {
+        Gc::node_addr(self)
     }
 
-    fn deroot_children(&self) {
-        unsafe { 
-            let ptr = self.gc_node_ptr.as_ref();
-            ptr.val.deroot_children();
-        }
+    fn is_root(&self) -> bool {
+        Gc::is_root(self)
     }
 
     fn root(&self) {
-        if !self.root.get() {
-            self.root.set(true);
-            unsafe {
-                let r = self.gc_node_ptr.as_ref();
-                let mut data = r.data.get();
-                data.add_roots();
-                r.data.set(data);
-            }
-        }
+        Gc::root(self)
     }
 
     fn deroot(&self) {
-        if self.root.get() {
-            self.root.set(false);
-            unsafe {
-                let r = self.gc_node_ptr.as_ref();
-                let mut data = r.data.get();
-                data.sub_roots();
-                r.data.set(data);
-            }
-        }
+        // SAFETY: only called by `deroot_children`, whose caller upholds it
+        unsafe { Gc::deroot(self) }
     }
-}
 
-impl<T: std::fmt::Display + Trace> std::fmt::Display for Gc<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.deref().fmt(f)
+    fn data(&self) -> &Cell<GcData> {
+        // SAFETY: the node outlives the handle
+        unsafe { &self.gc_node_ptr.as_ref().data }
     }
-}
 
-impl<T: PartialEq + Trace> PartialEq for Gc<T> {
-    fn eq(&self, other: &Self) -> bool {
-        self.deref() == other.deref()
+    fn trace_target(&self, tracer: &mut dyn Tracer) {
+        // SAFETY: as above
+        unsafe { self.gc_node_ptr.as_ref().val.trace(tracer) }
     }
 }
 
-impl<T: std::fmt::Debug + Trace> std::fmt::Debug for Gc<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Gc({:?})", self.deref())
-    }
+/// A `Gc` handle found by tracing.
+pub struct GcEdge<'a> {
+    handle: &'a dyn Handle,
+    // Found through shared ownership, like an `Rc`, so it can have owners
+    // outside the heap
+    shared: bool,
 }
 
-impl<T: Trace> Trace for Option<T> {
-    fn trace(&self) {
-        if let Some(ref val) = self {
-            val.trace();
-        }
+impl GcEdge<'_> {
+    /// The address of the object the handle points to, as the inspection
+    /// tools identify it.
+    pub fn addr(&self) -> *const () {
+        self.handle.node_addr()
     }
 
-    fn root_children(&self) {
-        if let Some(ref val) = self {
-            val.root_children();
-        }
+    pub fn is_root(&self) -> bool {
+        self.handle.is_root()
     }
 
-    fn deroot_children(&self) {
-        if let Some(ref val) = self {
-            val.deroot_children();
+    /// Whether the handle was reached through shared ownership, like an
+    /// `Rc`. Those stay rooted inside the heap, as whatever else owns them
+    /// may not be.
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    pub(crate) fn into_shared(self) -> Self {
+        GcEdge { shared: true, ..self }
+    }
+
+    pub(crate) fn root(&self) {
+        if !self.shared {
+            self.handle.root();
         }
     }
 
-    fn root(&self) {
-        if let Some(ref val) = self {
-            val.root();
+    pub(crate) fn deroot(&self) {
+        if !self.shared {
+            self.handle.deroot();
         }
     }
 
-    fn deroot(&self) {
-        if let Some(ref val) = self {
-            val.deroot();
+    // Marks the object, returning false if it already was
+    pub(crate) fn mark(&self) -> bool {
+        let cell = self.handle.data();
+        let mut data = cell.get();
+        if data.is_marked() {
+            return false;
         }
+        data.mark();
+        cell.set(data);
+        true
+    }
+
+    pub(crate) fn finalizes(&self) -> bool {
+        self.handle.data().get().finalizes()
+    }
+
+    /// Traces the object the handle points to.
+    pub(crate) fn trace_target(&self, tracer: &mut dyn Tracer) {
+        self.handle.trace_target(tracer);
     }
 }
 
-impl<T: Trace, E> Trace for Result<T, E> {
-    fn trace(&self) {
-        if let Ok(ref val) = self {
-            val.trace();
-        }
+impl<T: std::fmt::Display + Trace + ?Sized> std::fmt::Display for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.deref().fmt(f)
     }
+}
 
-    fn root_children(&self) {
-        if let Ok(ref val) = self {
-            val.root_children();
-        }
+impl<T: PartialEq + Trace + ?Sized> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.deref() == other.deref()
     }
+}
 
-    fn deroot_children(&self) {
-        if let Ok(ref val) = self {
-            val.deroot_children();
-        }
+impl<T: std::fmt::Debug + Trace + ?Sized> std::fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Gc({:?})", self.deref())
     }
+}
 
-    fn root(&self) {
-        if let Ok(ref val) = self {
-            val.root();
+unsafe impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        if let Some(ref val) = self {
+            val.trace(tracer);
         }
     }
+}
 
-    fn deroot(&self) {
-        if let Ok(ref val) = self {
-            val.deroot();
+unsafe impl<T: Trace, E: Trace> Trace for Result<T, E> {
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        match self {
+            Ok(val) => val.trace(tracer),
+            Err(err) => err.trace(tracer),
         }
     }
 }
+
+unsafe impl<T: NoGc> NoGcImpl for Option<T> {}
+
+unsafe impl<T: NoGc, E: NoGc> NoGcImpl for Result<T, E> {}
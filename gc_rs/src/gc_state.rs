@@ -1,32 +1,123 @@
+use std::any::TypeId;
 use std::ptr::NonNull;
+use std::marker::PhantomData;
+use std::panic::Location;
 use std::cell::{Cell, RefCell};
 use std::time::{Duration, Instant};
 
 use crate::traits::*;
+use crate::gc::GcEdge;
+use crate::stats::*;
+use crate::hooks::*;
+use crate::verify::*;
+use crate::profiler::Sampler;
+use crate::log::*;
+use crate::trace::TraceLog;
+#[cfg(unix)]
+use crate::server::Server;
+#[cfg(feature = "stats")]
+use crate::census::*;
 
 pub struct GcState {
     list_head: Option<NonNull<GcNode<dyn Trace>>>,
     last_gc: Instant,
+    next_id: u64,
     pub gc_duration: Duration,
+    stats: GcStats,
+    // Set from the start of a collection to the end of its sweep. Hooks can
+    // run (and allocate) in between phases, so this also stops nested
+    // collections.
+    collection: Option<CollectionRecord>,
+    // Set once the collection in progress has finished marking. Nodes
+    // allocated from then on start out marked, as nothing will trace them.
+    marking_done: bool,
+    // Live objects whose type finalizes, and how many of them the last
+    // mark left unmarked. Collections only look for objects to finalize if
+    // there are some of the latter.
+    finalizable: usize,
+    unmarked_finalizable: usize,
+    pub(crate) last_collection: Option<CollectionRecord>,
+    pub(crate) log_level: GcLogLevel,
+    pub(crate) trace_events: Option<TraceLog>,
+    #[cfg(unix)]
+    pub(crate) server: Option<Server>,
+    pub(crate) hooks: GcHooks,
+    pub(crate) verify_after_gc: bool,
+    pub(crate) sampler: Option<Sampler>,
+    #[cfg(feature = "stats")]
+    pub(crate) census: CensusTable,
 }
 
 #[derive(Debug)]
 pub struct GcNode<T: Trace + ?Sized + 'static> {
     pub data: Cell<GcData>,
     pub next: Option<NonNull<GcNode<dyn Trace>>>,
+    /// Unique for the life of the heap, unlike the address
+    pub id: u64,
+    /// Of the type allocated, for naming it in the inspection tools and
+    /// downcasting a `Gc<dyn Trait>`
+    pub type_info: &'static TypeInfo,
+    #[cfg(feature = "track-alloc-sites")]
+    pub alloc_site: &'static Location<'static>,
     pub val: T,
 }
 
+/// What a node knows about the type it was allocated as. There's one for
+/// each type, so a node only needs a pointer to it.
+#[derive(Debug)]
+pub struct TypeInfo {
+    pub id: TypeId,
+    name: fn() -> &'static str,
+}
+
+impl TypeInfo {
+    pub fn of<T: 'static>() -> &'static TypeInfo {
+        &TypeInfoOf::<T>::INFO
+    }
+
+    pub fn name(&self) -> &'static str {
+        (self.name)()
+    }
+}
+
+struct TypeInfoOf<T>(PhantomData<T>);
+
+impl<T: 'static> TypeInfoOf<T> {
+    const INFO: TypeInfo = TypeInfo {
+        id: TypeId::of::<T>(),
+        name: std::any::type_name::<T>,
+    };
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
 pub struct GcData {
     data: usize,
 }
 
+const MARK_BIT: usize = 1 << 63;
+// Set for the life of a node if its type finalizes
+const FINALIZE_BIT: usize = 1 << 62;
+const ROOTS: usize = !(MARK_BIT | FINALIZE_BIT);
+
+// The value of the mark bit that means "marked". It flips after every
+// collection, so survivors become unmarked without being written to.
+thread_local!(static MARK_EPOCH: Cell<usize> = const { Cell::new(MARK_BIT) });
+
+pub fn mark_epoch() -> usize {
+    MARK_EPOCH.with(|epoch| epoch.get())
+}
+
+fn flip_mark_epoch() {
+    MARK_EPOCH.with(|epoch| epoch.set(epoch.get() ^ MARK_BIT));
+}
+
 impl GcData {
     pub fn new() -> Self {
-        // Start off rooted
-        GcData { data: 1 }
+        // Start off rooted and unmarked in the current epoch
+        let mut data = GcData { data: 1 };
+        data.unmark();
+        data
     }
 
     pub fn is_root(&self) -> bool {
@@ -34,7 +125,7 @@ impl GcData {
     }
 
     pub fn get_roots(&self) -> usize {
-        self.data & !(1 << 63)
+        self.data & ROOTS
     }
 
     pub fn add_roots(&mut self) {
@@ -44,21 +135,53 @@ impl GcData {
 
     pub fn sub_roots(&mut self) {
         // Might add checking that it's less than (1 << 63) - 1
-        if self.data &!(1 << 63) > 0 {
+        if self.data & ROOTS > 0 {
             self.data -= 1;
         }
     }
 
     pub fn mark(&mut self) {
-        self.data |= 1 << 63;
+        self.data = (self.data & !MARK_BIT) | mark_epoch();
     }
 
     pub fn unmark(&mut self) {
-        self.data &= !(1 << 63);
+        self.data = (self.data & !MARK_BIT) | (mark_epoch() ^ MARK_BIT);
     }
 
     pub fn is_marked(&self) -> bool {
-        self.data & (1 << 63) != 0
+        self.data & MARK_BIT == mark_epoch()
+    }
+
+    pub fn finalizes(&self) -> bool {
+        self.data & FINALIZE_BIT != 0
+    }
+
+    fn set_finalizes(&mut self) {
+        self.data |= FINALIZE_BIT;
+    }
+}
+
+impl Default for GcData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Marks everything reachable from the handles it's given, counting the
+// objects it marks that finalize
+#[derive(Default)]
+struct Marker {
+    finalizable: usize,
+}
+
+impl Tracer for Marker {
+    fn edge(&mut self, edge: GcEdge<'_>) {
+        if edge.mark() {
+            if edge.finalizes() {
+                self.finalizable += 1;
+            }
+            edge.trace_target(self);
+        }
     }
 }
 
@@ -67,66 +190,135 @@ impl GcState {
         GcState {
             list_head: None,
             last_gc: Instant::now(),
+            next_id: 1,
             gc_duration: Duration::from_secs(2),
+            stats: GcStats::default(),
+            collection: None,
+            marking_done: false,
+            finalizable: 0,
+            unmarked_finalizable: 0,
+            last_collection: None,
+            log_level: GcLogLevel::from_env(),
+            trace_events: None,
+            #[cfg(unix)]
+            server: None,
+            hooks: GcHooks::default(),
+            verify_after_gc: false,
+            sampler: None,
+            #[cfg(feature = "stats")]
+            census: CensusTable::new(),
         }
     }
 
-    pub unsafe fn get_ptrs_len(&self) -> usize {
-        let mut len = 0;
-        let mut curr = self.list_head;
-        while let Some(node) = curr {
-            len += 1;
-            curr = (*node.as_ptr()).next;
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            roots: rooted_nodes(),
+            ..self.stats
         }
-        len
     }
 
-    pub unsafe fn get_roots_len(&self) -> usize {
-        let mut len = 0;
-        let mut curr = self.list_head;
-        while let Some(node) = curr {
-            let r = node.as_ref();
-            if r.data.get().is_root() {
-                len += 1;
-            }
-            curr = r.next;
-        }
-        len
+    /// Every live node, most recently allocated first.
+    pub fn nodes(&self) -> Nodes<'_> {
+        Nodes { curr: self.list_head, _state: PhantomData }
     }
 
     pub fn try_collect_garbage(&mut self) {
+        if self.should_collect() {
+            self.collect_garbage_because(GcReason::Timer);
+        }
+    }
+
+    /// Whether the timer has run out, resetting it if so.
+    pub fn should_collect(&mut self) -> bool {
         let now = Instant::now();
-        if now.duration_since(self.last_gc) > self.gc_duration {
+        if self.collection.is_none() && now.duration_since(self.last_gc) > self.gc_duration {
             self.last_gc = now;
-            self.collect_garbage();
+            true
+        } else {
+            false
         }
     }
 
+    /// Runs a whole collection with the state already borrowed. Lifecycle
+    /// hooks don't run here, as they need to be able to use the heap - use
+    /// `gc_rs::collect_garbage` for that.
     pub fn collect_garbage(&mut self) {
-        // Traverse the list and trace all nodes that have roots
+        self.collect_garbage_because(GcReason::Explicit);
+    }
+
+    // Finalizers run with the state borrowed here, so any that allocate
+    // will panic, which the guard turns into an aborted collection
+    fn collect_garbage_because(&mut self, reason: GcReason) {
+        if self.begin_collection(reason) {
+            let guard = BorrowedCollectionGuard(self);
+            guard.0.mark();
+            let unmarked = guard.0.unmarked_nodes();
+            let first_new_id = guard.0.next_id;
+            run_finalizers(&unmarked);
+            guard.0.mark_resurrected(&unmarked, first_new_id);
+            guard.0.sweep();
+            guard.0.end_collection();
+        }
+    }
+
+    // False if a collection is already in progress
+    pub(crate) fn begin_collection(&mut self, reason: GcReason) -> bool {
+        if self.collection.is_some() {
+            return false;
+        }
+        self.collection = Some(CollectionRecord::new(reason, self.stats()));
+        true
+    }
+
+    // Traverse the list and trace all nodes that have roots
+    pub(crate) fn mark(&mut self) {
+        let start = Instant::now();
+        let mut roots_scanned = 0;
+        let mut marker = Marker::default();
         unsafe {
             let mut curr = self.list_head;
             while let Some(mut node) = curr {
                 let node = node.as_mut();
                 let mut data = node.data.get();
                 if data.is_root() {
-                    data.mark();
-                    node.data.set(data); 
-                    node.val.trace();
+                    roots_scanned += 1;
+                    // It may have been marked (and traced) through another
+                    // root already
+                    if !data.is_marked() {
+                        data.mark();
+                        node.data.set(data);
+                        if data.finalizes() {
+                            marker.finalizable += 1;
+                        }
+                        node.val.trace(&mut marker);
+                    }
                 }
                 curr = node.next;
             }
         }
+        self.unmarked_finalizable = self.finalizable - marker.finalizable;
+        self.marking_done = true;
+        if let Some(record) = &mut self.collection {
+            let elapsed = start.elapsed();
+            record.roots_scanned += roots_scanned;
+            record.mark += elapsed;
+            if let Some(trace) = &mut self.trace_events {
+                trace.phase("mark", record.number, start, elapsed);
+            }
+        }
+    }
 
-        // Traverse again, removing and freeing nodes that are not marked.
-        // Also unmark all nodes that are marked.
+    // Traverse again, removing and freeing nodes that are not marked.
+    // Survivors are left as they are - flipping the epoch afterwards
+    // unmarks them all at once.
+    pub(crate) fn sweep(&mut self) {
+        let start = Instant::now();
         unsafe {
             let mut curr = self.list_head;
             let mut prev: Option<NonNull<GcNode<dyn Trace>>> = None;
             while let Some(mut cnode) = curr {
                 let node = cnode.as_mut();
                 if node.data.get().is_marked() {
-                    node.data.get_mut().unmark();
                     curr = node.next;
                     prev = Some(cnode);
                 } else {
@@ -136,21 +328,127 @@ impl GcState {
                         self.list_head = node.next;
                     }
                     curr = node.next;
+                    let size = std::mem::size_of_val(node);
+                    self.stats.live_objects -= 1;
+                    self.stats.live_bytes -= size;
+                    self.stats.objects_freed += 1;
+                    self.stats.bytes_freed += size;
+                    if node.data.get().finalizes() {
+                        self.finalizable -= 1;
+                    }
+                    #[cfg(feature = "stats")]
+                    census_sub(&mut self.census, node.type_info.name(), size);
+                    if let Some(sampler) = &mut self.sampler {
+                        sampler.on_free(cnode.as_ptr() as *const ());
+                    }
                     // Might free?
                     let _ = *Box::from_raw(node);
                 }
             }
         }
+        if let Some(record) = &mut self.collection {
+            let elapsed = start.elapsed();
+            record.sweep += elapsed;
+            if let Some(trace) = &mut self.trace_events {
+                trace.phase("sweep", record.number, start, elapsed);
+            }
+        }
+    }
+
+    // What the sweep is about to free, unless a finalizer keeps it alive.
+    // Empty if none of it finalizes, as then no finalizer can run.
+    fn unmarked_nodes(&self) -> Vec<NonNull<GcNode<dyn Trace>>> {
+        if self.unmarked_finalizable == 0 {
+            return Vec::new();
+        }
+        self.nodes().filter(|node| !node.data.get().is_marked()).map(NonNull::from).collect()
+    }
+
+    // Marks whatever the finalizers of `finalized` kept alive. One that
+    // clones a handle roots its object, which is marked again along with
+    // everything it reaches. Nodes they allocate start out marked, but
+    // nothing has traced them, so they're traced here too.
+    fn mark_resurrected(&self, finalized: &[NonNull<GcNode<dyn Trace>>], first_new_id: u64) {
+        // New nodes are at the front of the list
+        let mut marker = Marker::default();
+        for node in self.nodes().take_while(|node| node.id >= first_new_id) {
+            node.val.trace(&mut marker);
+        }
+        for node in finalized {
+            // SAFETY: see `run_finalizers`
+            let node = unsafe { node.as_ref() };
+            let mut data = node.data.get();
+            if data.is_root() && !data.is_marked() {
+                data.mark();
+                node.data.set(data);
+                node.val.trace(&mut marker);
+            }
+        }
+    }
+
+    pub(crate) fn end_collection(&mut self) {
+        let Some(mut record) = self.collection.take() else { return };
+        self.marking_done = false;
+        flip_mark_epoch();
+        let pause = record.mark + record.sweep;
+
+        self.stats.collections += 1;
+        self.stats.last_pause = pause;
+        self.stats.max_pause = self.stats.max_pause.max(pause);
+        self.stats.total_gc_time += pause;
+
+        if self.verify_after_gc {
+            if let Err(err) = self.verify() {
+                panic!("heap verification failed after collection: {}", err);
+            }
+        }
+
+        record.after = self.stats();
+        self.log_collection(&record);
+        if let Some(trace) = &mut self.trace_events {
+            trace.collection(&record);
+        }
+        self.last_collection = Some(record);
     }
 
+    // Gives up on the collection in progress without freeing anything,
+    // unmarking whatever it had marked so the next one starts afresh
+    fn abort_collection(&mut self) {
+        if self.collection.take().is_none() {
+            return;
+        }
+        self.marking_done = false;
+        for node in self.nodes() {
+            let mut data = node.data.get();
+            data.unmark();
+            node.data.set(data);
+        }
+    }
+
+    /// Frees every node, rooted or not.
+    ///
+    /// # Safety
+    /// Any `Gc` still alive afterwards dangles.
     pub unsafe fn refresh(&mut self) {
         let mut curr = self.list_head;
         while let Some(mut cnode) = curr {
             let node = cnode.as_mut();
             curr = node.next;
+            let size = std::mem::size_of_val(node);
+            self.stats.objects_freed += 1;
+            self.stats.bytes_freed += size;
             let _ = *Box::from_raw(node);
         }
         self.list_head = None;
+        self.stats.live_objects = 0;
+        self.finalizable = 0;
+        self.stats.live_bytes = 0;
+        #[cfg(feature = "stats")]
+        self.census.clear();
+        if let Some(sampler) = &mut self.sampler {
+            sampler.clear();
+        }
+        reset_rooted_nodes();
     }
 
     pub fn set_gc_duration(&mut self, duration: Duration) {
@@ -158,24 +456,201 @@ impl GcState {
     }
 }
 
+pub struct Nodes<'a> {
+    curr: Option<NonNull<GcNode<dyn Trace>>>,
+    _state: PhantomData<&'a GcState>,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = &'a GcNode<dyn Trace>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // SAFETY: nodes are only freed by the sweep, which needs the state
+        // borrowed mutably, so they outlive the borrow this holds
+        let node = unsafe { self.curr?.as_ref() };
+        self.curr = node.next;
+        Some(node)
+    }
+}
+
+impl Default for GcState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // This is the actual GC
 thread_local!(pub static GC_STATE: RefCell<GcState> = RefCell::new(GcState::new()));
 
 impl<T: Trace> GcNode<T> {
+    #[cfg_attr(feature = "track-alloc-sites", track_caller)]
     pub fn new(val: T) -> NonNull<Self> {
-        GC_STATE.with(|state| {
+        #[cfg(feature = "track-alloc-sites")]
+        let alloc_site = Location::caller();
+
+        if GC_STATE.with(|state| state.borrow_mut().should_collect()) {
+            collect_garbage_because(GcReason::Timer);
+        }
+
+        let (ptr, queries_pending) = GC_STATE.with(|state| {
             let mut state = state.borrow_mut();
-            state.try_collect_garbage();
+            let mut data = GcData::new();
+            if state.marking_done {
+                // Allocated by a hook after marking, so it needs to survive
+                // the sweep
+                data.mark();
+            }
+            if T::finalizes() {
+                data.set_finalizes();
+                state.finalizable += 1;
+            }
             let ptr = Box::into_raw(Box::new(GcNode {
-                data: Cell::new(GcData::new()),
+                data: Cell::new(data),
                 next: state.list_head.take(),
+                id: state.next_id,
+                type_info: TypeInfo::of::<T>(),
+                #[cfg(feature = "track-alloc-sites")]
+                alloc_site,
                 val,
             }));
 
+            state.next_id += 1;
+            let size = std::mem::size_of::<Self>();
+            state.stats.live_objects += 1;
+            state.stats.live_bytes += size;
+            state.stats.bytes_allocated += size;
+            #[cfg(feature = "stats")]
+            census_add(&mut state.census, std::any::type_name::<T>(), size);
+            if let Some(sampler) = &mut state.sampler {
+                sampler.on_alloc(ptr as *const (), size);
+            }
+            add_rooted_node();
+
             // SAFETY: box guaranteed to be non null (same for both)
             state.list_head = Some(unsafe { NonNull::new_unchecked(ptr) });
-            unsafe { NonNull::new_unchecked(ptr) }
+            (unsafe { NonNull::new_unchecked(ptr) }, state.queries_pending())
+        });
+
+        run_alloc_hooks();
+        if queries_pending {
+            GC_STATE.with(|state| state.borrow().answer_queries());
+        }
+        ptr
+    }
+}
+
+impl<T: Trace + ?Sized> GcNode<T> {
+    /// Where the node was allocated, if the `track-alloc-sites` feature is
+    /// enabled.
+    pub fn alloc_site(&self) -> Option<&'static Location<'static>> {
+        #[cfg(feature = "track-alloc-sites")]
+        return Some(self.alloc_site);
+
+        #[cfg(not(feature = "track-alloc-sites"))]
+        return None;
+    }
+
+    pub fn add_root(&self) {
+        let mut data = self.data.get();
+        if !data.is_root() {
+            add_rooted_node();
+        }
+        data.add_roots();
+        self.data.set(data);
+    }
+
+    pub fn sub_root(&self) {
+        let mut data = self.data.get();
+        match data.get_roots() {
+            0 => note_saturated_deroot(self as *const Self as *const (), self.type_info.name()),
+            1 => sub_rooted_node(),
+            _ => {}
+        }
+        data.sub_roots();
+        self.data.set(data);
+    }
+}
+
+/// Runs a whole collection, calling any lifecycle hooks registered on this
+/// thread's heap between the phases. Hooks may allocate, collect or read
+/// stats. Objects allocated by `on_gc_start` hooks are marked like any
+/// other, and those allocated once marking is done survive the collection
+/// in progress. If a hook panics, the collection is abandoned.
+pub fn collect_garbage() {
+    collect_garbage_because(GcReason::Explicit);
+}
+
+fn collect_garbage_because(reason: GcReason) {
+    let before = GC_STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.begin_collection(reason).then(|| state.stats())
+    });
+    let Some(before) = before else { return };
+
+    let after = {
+        let _guard = CollectionGuard;
+
+        run_hooks(|hooks| &mut hooks.on_gc_start, |f| f(&before));
+
+        GC_STATE.with(|state| state.borrow_mut().mark());
+
+        run_hooks(|hooks| &mut hooks.on_mark_done, |f| f());
+
+        // The state isn't borrowed while finalizers run, so they can
+        // allocate like hooks can
+        let (unmarked, first_new_id) = GC_STATE.with(|state| {
+            let state = state.borrow();
+            (state.unmarked_nodes(), state.next_id)
+        });
+        run_finalizers(&unmarked);
+        GC_STATE.with(|state| state.borrow().mark_resurrected(&unmarked, first_new_id));
+
+        GC_STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.sweep();
+            state.end_collection();
+            state.stats()
         })
+    };
+
+    let delta = after.since(&before);
+    run_hooks(|hooks| &mut hooks.on_gc_end, |f| f(&delta));
+}
+
+// Runs finalizers on everything the sweep is about to free, before any of
+// it is, so they can still use the handles inside
+fn run_finalizers(nodes: &[NonNull<GcNode<dyn Trace>>]) {
+    for node in nodes {
+        // SAFETY: nodes are only freed by the sweep, and no other
+        // collection can start until this one has ended
+        let node = unsafe { node.as_ref() };
+        if node.data.get().finalizes() {
+            node.val.finalize_glue();
+        }
+    }
+}
+
+// Aborts the collection in progress if it's dropped before the collection
+// ends, so a panicking hook or finalizer doesn't leave the heap
+// mid-collection for good
+struct CollectionGuard;
+
+impl Drop for CollectionGuard {
+    fn drop(&mut self) {
+        GC_STATE.with(|state| {
+            if let Ok(mut state) = state.try_borrow_mut() {
+                state.abort_collection();
+            }
+        });
+    }
+}
+
+// The same, for a collection run with the state already borrowed
+struct BorrowedCollectionGuard<'a>(&'a mut GcState);
+
+impl Drop for BorrowedCollectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.abort_collection();
     }
 }
 
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::panic::Location;
+
+use crate::gc::GcEdge;
+use crate::gc_state::*;
+use crate::traits::*;
+
+/// A `Gc` handle found inside a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub addr: *const (),
+    /// Whether the handle itself is rooted, which it shouldn't be inside
+    /// the heap unless it's shared
+    pub rooted: bool,
+    /// Reached through shared ownership, like an `Rc`
+    pub shared: bool,
+}
+
+// Records the handles it's given, without following them
+struct EdgeCollector(Vec<Edge>);
+
+impl Tracer for EdgeCollector {
+    fn edge(&mut self, edge: GcEdge<'_>) {
+        self.0.push(Edge { addr: edge.addr(), rooted: edge.is_root(), shared: edge.is_shared() });
+    }
+}
+
+/// The handles directly held by `node`.
+pub fn edges_of(node: &GcNode<dyn Trace>) -> Vec<Edge> {
+    let mut edges = EdgeCollector(Vec::new());
+    node.val.trace(&mut edges);
+    edges.0
+}
+
+pub fn node_addr(node: &GcNode<dyn Trace>) -> *const () {
+    node as *const GcNode<dyn Trace> as *const ()
+}
+
+#[derive(Debug, Clone)]
+pub struct GraphNode {
+    pub addr: *const (),
+    pub id: u64,
+    pub type_name: &'static str,
+    pub alloc_site: Option<&'static Location<'static>>,
+    /// Shallow size of the node, header included
+    pub size: usize,
+    pub roots: usize,
+    /// Indices of the nodes this one references
+    pub edges: Vec<usize>,
+}
+
+/// A copy of the shape of the heap: one entry per live node, in list order.
+#[derive(Debug, Clone, Default)]
+pub struct HeapGraph {
+    pub nodes: Vec<GraphNode>,
+    pub index: HashMap<*const (), usize>,
+}
+
+impl HeapGraph {
+    pub fn new(state: &GcState) -> Self {
+        let mut nodes = Vec::new();
+        let mut index = HashMap::new();
+        let mut addr_edges = Vec::new();
+        for node in state.nodes() {
+            index.insert(node_addr(node), nodes.len());
+            nodes.push(GraphNode {
+                addr: node_addr(node),
+                id: node.id,
+                type_name: node.type_info.name(),
+                alloc_site: node.alloc_site(),
+                size: std::mem::size_of_val(node),
+                roots: node.data.get().get_roots(),
+                edges: Vec::new(),
+            });
+            addr_edges.push(edges_of(node));
+        }
+
+        // Edges to anything not on the list (which would be a bug) are dropped
+        for (node, edges) in nodes.iter_mut().zip(addr_edges) {
+            node.edges = edges.iter().filter_map(|edge| index.get(&edge.addr).copied()).collect();
+        }
+
+        HeapGraph { nodes, index }
+    }
+
+    pub fn roots(&self) -> impl Iterator<Item = usize> + '_ {
+        self.nodes.iter().enumerate().filter(|(_, node)| node.roots > 0).map(|(i, _)| i)
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::gc_state::*;
+use crate::graph::*;
+use crate::json;
+
+// Writes the heap in the `.heapsnapshot` format Chrome DevTools' memory tab
+// loads. The format is a flat list of numbers per node and per edge, with
+// edges grouped by the node they come from (in node order) and pointing at
+// the offset of the target node in the node list.
+
+const NODE_FIELDS: usize = 6;
+
+const META: &str = r#"{"node_fields":["type","name","id","self_size","edge_count","trace_node_id"],"node_types":[["hidden","array","string","object","code","closure","regexp","number","native","synthetic","concatenated string","sliced string","symbol","bigint"],"string","number","number","number","number","number"],"edge_fields":["type","name_or_index","to_node"],"edge_types":[["context","element","property","internal","hidden","shortcut","weak"],"string_or_number","node"],"trace_function_info_fields":["function_id","name","script_name","script_id","line","column"],"trace_node_fields":["id","function_info_index","count","size","children"],"sample_fields":["timestamp_us","last_assigned_id"],"location_fields":["object_index","script_id","line","column"]}"#;
+
+const NODE_TYPE_OBJECT: usize = 3;
+const NODE_TYPE_SYNTHETIC: usize = 9;
+const EDGE_TYPE_ELEMENT: usize = 1;
+
+// Object ids start from 1
+const ROOT_ID: usize = 0;
+
+#[derive(Default)]
+struct Strings {
+    strings: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl Strings {
+    fn get(&mut self, s: &str) -> usize {
+        if let Some(&i) = self.index.get(s) {
+            return i;
+        }
+        self.strings.push(s.to_string());
+        self.index.insert(s.to_string(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+}
+
+impl GcState {
+    /// Writes every live object as a `.heapsnapshot`. Objects are named by
+    /// their type (and allocation site, if tracked) and identified by
+    /// their id, which unlike the address isn't reused, so objects can be
+    /// matched up across snapshots. A synthetic "(GC roots)" node
+    /// references every rooted object.
+    pub fn write_heap_snapshot<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let graph = HeapGraph::new(self);
+        let mut strings = Strings::default();
+
+        let roots: Vec<usize> = graph.roots().collect();
+        let mut nodes = vec![
+            NODE_TYPE_SYNTHETIC,
+            strings.get("(GC roots)"),
+            ROOT_ID,
+            0,
+            roots.len(),
+            0,
+        ];
+        for node in &graph.nodes {
+            let name = match node.alloc_site {
+                Some(site) => strings.get(&format!("{} @ {}", node.type_name, site)),
+                None => strings.get(node.type_name),
+            };
+            nodes.extend([
+                NODE_TYPE_OBJECT,
+                name,
+                node.id as usize,
+                node.size,
+                node.edges.len(),
+                0,
+            ]);
+        }
+
+        // Snapshot node i + 1 is graph node i, after the root
+        let mut edges = Vec::new();
+        for (i, &root) in roots.iter().enumerate() {
+            edges.extend([EDGE_TYPE_ELEMENT, i, (root + 1) * NODE_FIELDS]);
+        }
+        for node in &graph.nodes {
+            for (i, &to) in node.edges.iter().enumerate() {
+                edges.extend([EDGE_TYPE_ELEMENT, i, (to + 1) * NODE_FIELDS]);
+            }
+        }
+
+        write!(
+            w,
+            r#"{{"snapshot":{{"meta":{},"node_count":{},"edge_count":{},"trace_function_count":0}},"#,
+            META,
+            nodes.len() / NODE_FIELDS,
+            edges.len() / 3,
+        )?;
+        write!(w, "\n\"nodes\":")?;
+        json::write_array(&mut w, nodes)?;
+        write!(w, ",\n\"edges\":")?;
+        json::write_array(&mut w, edges)?;
+        write!(w, ",\n\"trace_function_infos\":[],\"trace_tree\":[],\"samples\":[],\"locations\":[],")?;
+        write!(w, "\n\"strings\":[")?;
+        for (i, s) in strings.strings.iter().enumerate() {
+            if i > 0 {
+                write!(w, ",")?;
+            }
+            json::write_str(&mut w, s)?;
+        }
+        writeln!(w, "]}}")
+    }
+}
+
+/// Writes this thread's heap to `path` as a `.heapsnapshot` file.
+pub fn write_heap_snapshot(path: impl AsRef<Path>) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    GC_STATE.with(|state| state.borrow().write_heap_snapshot(&mut w))?;
+    w.flush()
+}
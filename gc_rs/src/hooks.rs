@@ -0,0 +1,111 @@
+use crate::gc_state::GC_STATE;
+use crate::stats::GcStats;
+
+pub type StatsHook = Box<dyn FnMut(&GcStats)>;
+pub type PhaseHook = Box<dyn FnMut()>;
+
+pub struct AllocThresholdHook {
+    every: usize,
+    next: usize,
+    hook: StatsHook,
+}
+
+/// Callbacks registered on a heap. They are only ever called while
+/// `GC_STATE` is not borrowed, so they are free to allocate, collect or
+/// read stats.
+#[derive(Default)]
+pub struct GcHooks {
+    pub(crate) on_gc_start: Vec<StatsHook>,
+    pub(crate) on_mark_done: Vec<PhaseHook>,
+    pub(crate) on_gc_end: Vec<StatsHook>,
+    pub(crate) on_alloc_threshold: Vec<AllocThresholdHook>,
+}
+
+/// Called before marking with the stats as they were at the start.
+pub fn on_gc_start(hook: impl FnMut(&GcStats) + 'static) {
+    GC_STATE.with(|state| state.borrow_mut().hooks.on_gc_start.push(Box::new(hook)));
+}
+
+/// Called once marking is done and before anything is freed.
+pub fn on_mark_done(hook: impl FnMut() + 'static) {
+    GC_STATE.with(|state| state.borrow_mut().hooks.on_mark_done.push(Box::new(hook)));
+}
+
+/// Called after the sweep with the change in stats over the collection
+/// (see `GcStats::since`).
+pub fn on_gc_end(hook: impl FnMut(&GcStats) + 'static) {
+    GC_STATE.with(|state| state.borrow_mut().hooks.on_gc_end.push(Box::new(hook)));
+}
+
+/// Called each time another `bytes` bytes have been allocated on the heap,
+/// counting from when it was registered.
+pub fn on_alloc_threshold(bytes: usize, hook: impl FnMut(&GcStats) + 'static) {
+    assert!(bytes > 0, "allocation threshold must be non-zero");
+    GC_STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let next = state.stats().bytes_allocated + bytes;
+        state.hooks.on_alloc_threshold.push(AllocThresholdHook {
+            every: bytes,
+            next,
+            hook: Box::new(hook),
+        });
+    });
+}
+
+// Takes the hooks out of the state while they run, so they can use the
+// heap (and register more hooks, which are kept after the existing ones).
+pub(crate) fn run_hooks<H, S: Fn(&mut GcHooks) -> &mut Vec<H>>(
+    select: S,
+    mut call: impl FnMut(&mut H),
+) {
+    let hooks = GC_STATE.with(|state| std::mem::take(select(&mut state.borrow_mut().hooks)));
+    if hooks.is_empty() {
+        return;
+    }
+
+    let mut taken = TakenHooks { hooks, select };
+    for hook in taken.hooks.iter_mut() {
+        call(hook);
+    }
+}
+
+// Puts hooks taken out by `run_hooks` back when dropped, so that a hook
+// panicking doesn't unregister every hook of its kind
+struct TakenHooks<H, S: Fn(&mut GcHooks) -> &mut Vec<H>> {
+    hooks: Vec<H>,
+    select: S,
+}
+
+impl<H, S: Fn(&mut GcHooks) -> &mut Vec<H>> Drop for TakenHooks<H, S> {
+    fn drop(&mut self) {
+        GC_STATE.with(|state| {
+            if let Ok(mut state) = state.try_borrow_mut() {
+                let added = (self.select)(&mut state.hooks);
+                self.hooks.append(added);
+                *added = std::mem::take(&mut self.hooks);
+            }
+        });
+    }
+}
+
+pub(crate) fn run_alloc_hooks() {
+    let due = GC_STATE.with(|state| {
+        let state = state.borrow();
+        let allocated = state.stats().bytes_allocated;
+        state.hooks.on_alloc_threshold.iter().any(|h| allocated >= h.next)
+    });
+    if !due {
+        return;
+    }
+
+    run_hooks(
+        |hooks| &mut hooks.on_alloc_threshold,
+        |threshold| {
+            let stats = crate::stats::stats();
+            if stats.bytes_allocated >= threshold.next {
+                threshold.next = stats.bytes_allocated + threshold.every;
+                (threshold.hook)(&stats);
+            }
+        },
+    );
+}
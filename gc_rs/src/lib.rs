@@ -1,13 +1,56 @@
-#![feature(log_syntax)]
+#![cfg_attr(feature = "nightly", feature(allocator_api, coerce_unsized, unsize))]
 
 pub mod gc_state;
 pub mod traits;
 pub mod gc;
+pub mod stats;
+pub mod hooks;
+pub mod graph;
+pub mod heapsnapshot;
+pub mod snapshot;
+pub mod dominators;
+pub mod dot;
+pub mod census;
+pub mod retainers;
+pub mod verify;
+pub mod profiler;
+pub mod log;
+pub mod trace;
+pub mod server;
+mod json;
 
-pub use gc_rs_derive::Trace;
+pub use gc_rs_derive::{NoGc, Trace};
 
-pub use gc::{Gc, GcRefMut};
+pub use gc::{Gc, GcEdge, GcRefMut};
 
-pub use gc_state::{set_gc_duration, GC_STATE};
+pub use gc_state::{collect_garbage, set_gc_duration, GC_STATE};
 
-pub use traits::Trace;
+pub use stats::{stats, GcStats};
+
+pub use heapsnapshot::write_heap_snapshot;
+
+pub use snapshot::{snapshot, DiffEntry, HeapSnapshot, SnapshotDiff, SnapshotObject};
+
+pub use dominators::{DominatorTree, TypeRetained};
+
+pub use dot::{dump_dot, DotOptions};
+
+pub use census::{census, census_by_site, CensusEntry};
+
+pub use retainers::{retaining_path, RetainingPath};
+
+pub use verify::{leak_warnings, set_verify_after_gc, verify_heap, HeapVerifyError, LeakWarning, Violation};
+
+pub use profiler::{set_sample_interval, write_collapsed_stacks};
+
+pub use log::{last_collection, set_gc_log_level, CollectionRecord, GcLogLevel, GcReason};
+
+pub use trace::{flush_trace_events, set_trace_events, trace_clock_us};
+
+pub use server::safepoint;
+#[cfg(unix)]
+pub use server::{start_inspection_server, stop_inspection_server};
+
+pub use hooks::{on_alloc_threshold, on_gc_end, on_gc_start, on_mark_done};
+
+pub use traits::{deroot_children, root_children, Finalize, NoGc, NoGcImpl, Trace, Tracer};
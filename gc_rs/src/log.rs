@@ -0,0 +1,129 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::gc_state::*;
+use crate::stats::GcStats;
+
+// A `-verbose:gc` style log. Every collection is recorded, and if logging
+// is on the record is written to stderr as it finishes.
+
+/// How much is written to stderr for each collection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GcLogLevel {
+    #[default]
+    Off,
+    /// One line per collection
+    Collections,
+    /// The line, followed by the census of what survived
+    Census,
+}
+
+impl GcLogLevel {
+    /// Reads `GC_RS_LOG`: `1` logs collections and `2` adds the census.
+    /// Anything else (or nothing) is off.
+    pub fn from_env() -> Self {
+        match std::env::var("GC_RS_LOG").as_deref() {
+            Ok("1") => GcLogLevel::Collections,
+            Ok("2") => GcLogLevel::Census,
+            _ => GcLogLevel::Off,
+        }
+    }
+}
+
+/// Why a collection ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcReason {
+    /// An allocation found `gc_duration` had passed since the last one
+    Timer,
+    /// `collect_garbage` was called
+    Explicit,
+}
+
+impl fmt::Display for GcReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GcReason::Timer => write!(f, "timer"),
+            GcReason::Explicit => write!(f, "explicit"),
+        }
+    }
+}
+
+/// What happened in one collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollectionRecord {
+    /// Counts from 1, like `GcStats::collections`
+    pub number: usize,
+    pub reason: GcReason,
+    pub started: Instant,
+    pub before: GcStats,
+    pub after: GcStats,
+    /// Rooted objects the mark started from
+    pub roots_scanned: usize,
+    pub mark: Duration,
+    pub sweep: Duration,
+}
+
+impl CollectionRecord {
+    pub(crate) fn new(reason: GcReason, before: GcStats) -> Self {
+        CollectionRecord {
+            number: before.collections + 1,
+            reason,
+            started: Instant::now(),
+            before,
+            after: before,
+            roots_scanned: 0,
+            mark: Duration::ZERO,
+            sweep: Duration::ZERO,
+        }
+    }
+}
+
+impl fmt::Display for CollectionRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[gc_rs] gc #{} ({}): {} -> {} objects, {} -> {} bytes, {} roots, mark {:?}, sweep {:?}",
+            self.number,
+            self.reason,
+            self.before.live_objects,
+            self.after.live_objects,
+            self.before.live_bytes,
+            self.after.live_bytes,
+            self.roots_scanned,
+            self.mark,
+            self.sweep,
+        )
+    }
+}
+
+impl GcState {
+    pub fn set_log_level(&mut self, level: GcLogLevel) {
+        self.log_level = level;
+    }
+
+    /// The most recent collection to finish, whether or not it was logged.
+    pub fn last_collection(&self) -> Option<CollectionRecord> {
+        self.last_collection
+    }
+
+    pub(crate) fn log_collection(&self, record: &CollectionRecord) {
+        if self.log_level == GcLogLevel::Off {
+            return;
+        }
+        eprintln!("{}", record);
+        if self.log_level >= GcLogLevel::Census {
+            for entry in self.census() {
+                eprintln!("[gc_rs]   {:>8} objects {:>10} bytes  {}", entry.count, entry.bytes, entry.type_name);
+            }
+        }
+    }
+}
+
+/// Overrides `GC_RS_LOG` for this thread's heap.
+pub fn set_gc_log_level(level: GcLogLevel) {
+    GC_STATE.with(|state| state.borrow_mut().set_log_level(level));
+}
+
+pub fn last_collection() -> Option<CollectionRecord> {
+    GC_STATE.with(|state| state.borrow().last_collection())
+}
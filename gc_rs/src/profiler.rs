@@ -0,0 +1,107 @@
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::gc_state::*;
+
+// A sampling heap profiler. Roughly every `interval` bytes of allocation,
+// the next allocation captures a backtrace, which is kept for as long as the
+// object is alive. Capturing is slow, so this is much cheaper than recording
+// every allocation. Each sample is weighted by the bytes allocated since the
+// one before it, so the totals estimate the live bytes rather than just
+// the sizes of the sampled objects.
+
+struct Sample {
+    // Allocated since the previous sample, this object included
+    bytes: usize,
+    backtrace: Backtrace,
+}
+
+pub(crate) struct Sampler {
+    interval: usize,
+    // Bytes left to allocate before the next sample
+    countdown: usize,
+    samples: HashMap<*const (), Sample>,
+}
+
+impl Sampler {
+    fn new(interval: usize) -> Self {
+        Sampler { interval, countdown: interval, samples: HashMap::new() }
+    }
+
+    pub(crate) fn on_alloc(&mut self, addr: *const (), bytes: usize) {
+        if bytes < self.countdown {
+            self.countdown -= bytes;
+            return;
+        }
+        let bytes = self.interval - self.countdown + bytes;
+        self.countdown = self.interval;
+        self.samples.insert(addr, Sample { bytes, backtrace: Backtrace::force_capture() });
+    }
+
+    pub(crate) fn on_free(&mut self, addr: *const ()) {
+        self.samples.remove(&addr);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+// The function names in a backtrace, outermost call first, without the
+// frames for capturing it and for the allocation inside gc_rs.
+fn frames(backtrace: &Backtrace) -> Vec<String> {
+    // Matched after stripping any leading `<`, as in `<gc_rs::gc::Gc<T>>::new`
+    const INTERNAL: &[&str] = &["gc_rs::", "std::backtrace", "std::thread::local"];
+
+    let mut frames: Vec<String> = backtrace
+        .to_string()
+        .lines()
+        .filter_map(|line| {
+            let (index, name) = line.trim_start().split_once(": ")?;
+            index.parse::<usize>().ok()?;
+            Some(name.replace(';', ":"))
+        })
+        .skip_while(|f| {
+            let f = f.trim_start_matches('<');
+            INTERNAL.iter().any(|prefix| f.starts_with(prefix))
+        })
+        .collect();
+    frames.reverse();
+    frames
+}
+
+impl GcState {
+    /// Starts sampling about every `interval` bytes allocated, or stops
+    /// and forgets all samples for `None`.
+    pub fn set_sample_interval(&mut self, interval: Option<usize>) {
+        self.sampler = interval.map(|interval| Sampler::new(interval.max(1)));
+    }
+
+    /// Writes the estimated live bytes per allocating stack, one stack per
+    /// line in the collapsed format flamegraph tools take:
+    /// `outer;inner;innermost <bytes>`.
+    pub fn write_collapsed_stacks<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let Some(sampler) = &self.sampler else { return Ok(()) };
+
+        let mut stacks: HashMap<String, usize> = HashMap::new();
+        for sample in sampler.samples.values() {
+            *stacks.entry(frames(&sample.backtrace).join(";")).or_default() += sample.bytes;
+        }
+
+        let mut stacks: Vec<_> = stacks.into_iter().collect();
+        stacks.sort();
+        for (stack, bytes) in stacks {
+            writeln!(w, "{} {}", stack, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn set_sample_interval(interval: Option<usize>) {
+    GC_STATE.with(|state| state.borrow_mut().set_sample_interval(interval));
+}
+
+pub fn write_collapsed_stacks<W: Write>(w: W) -> io::Result<()> {
+    GC_STATE.with(|state| state.borrow().write_collapsed_stacks(w))
+}
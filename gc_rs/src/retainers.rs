@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::Location;
+
+use crate::gc::Gc;
+use crate::gc_state::*;
+use crate::graph::*;
+use crate::traits::*;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct PathStep {
+    pub type_name: &'static str,
+    pub addr: *const (),
+    pub alloc_site: Option<&'static Location<'static>>,
+}
+
+/// A chain of objects from a rooted one to the target, each holding a `Gc`
+/// to the next.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RetainingPath {
+    pub steps: Vec<PathStep>,
+}
+
+impl RetainingPath {
+    pub fn root(&self) -> &PathStep {
+        &self.steps[0]
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl fmt::Debug for PathStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} @ {:p}", self.type_name, self.addr)?;
+        if let Some(site) = self.alloc_site {
+            write!(f, " (allocated at {})", site)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for RetainingPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            if i == 0 {
+                write!(f, "(root) {:?}", step)?;
+            } else {
+                write!(f, "\n  -> {:?}", step)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GcState {
+    /// One shortest path from a rooted object to the object at `addr`, or
+    /// `None` if nothing rooted reaches it. `ignored_roots` roots of the
+    /// target itself are not counted, so that the handle used to ask
+    /// doesn't explain its own object.
+    pub fn retaining_path(&self, addr: *const (), ignored_roots: usize) -> Option<RetainingPath> {
+        let mut graph = HeapGraph::new(self);
+        let target = *graph.index.get(&addr)?;
+        let roots = &mut graph.nodes[target].roots;
+        *roots = roots.saturating_sub(ignored_roots);
+
+        // Breadth first from all the roots at once
+        let mut parent: Vec<Option<usize>> = vec![None; graph.nodes.len()];
+        let mut seen = vec![false; graph.nodes.len()];
+        let mut queue = VecDeque::new();
+        for root in graph.roots() {
+            seen[root] = true;
+            queue.push_back(root);
+        }
+
+        while let Some(i) = queue.pop_front() {
+            if i == target {
+                let mut steps = Vec::new();
+                let mut curr = Some(i);
+                while let Some(i) = curr {
+                    let node = &graph.nodes[i];
+                    steps.push(PathStep {
+                        type_name: node.type_name,
+                        addr: node.addr,
+                        alloc_site: node.alloc_site,
+                    });
+                    curr = parent[i];
+                }
+                steps.reverse();
+                return Some(RetainingPath { steps });
+            }
+            for &to in &graph.nodes[i].edges {
+                if !seen[to] {
+                    seen[to] = true;
+                    parent[to] = Some(i);
+                    queue.push_back(to);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Explains why `gc` is still alive, not counting `gc` itself as a root.
+pub fn retaining_path<T: Trace + ?Sized>(gc: &Gc<T>) -> Option<RetainingPath> {
+    let ignored_roots = if gc.is_root() { 1 } else { 0 };
+    GC_STATE.with(|state| state.borrow().retaining_path(gc.node_addr(), ignored_roots))
+}
@@ -0,0 +1,193 @@
+use crate::gc_state::*;
+
+// Answers inspection queries from other processes over a Unix socket. The
+// heap belongs to one thread, so a listener thread only accepts connections
+// and reads the query; the answer is written by the owning thread at its
+// next allocation or call to `safepoint`.
+//
+// The protocol is one query per connection: the client writes a line, and
+// the server writes the answer and closes the connection. Queries are
+// `stats`, `census` and `snapshot` (the `HeapSnapshot` JSON).
+
+#[cfg(unix)]
+mod socket {
+    use std::fs::{self, Permissions};
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{self, Receiver};
+    use std::sync::Arc;
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+
+    use crate::gc_state::*;
+
+    // Clients that stop talking shouldn't hold up the ones behind them, or
+    // the heap
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // How often the listener checks whether it's been stopped
+    const POLL: Duration = Duration::from_millis(20);
+
+    pub(crate) struct Server {
+        path: PathBuf,
+        queries: Receiver<(String, UnixStream)>,
+        // Set by the listener when it queues a query, so allocation only
+        // has to check a flag
+        pending: Arc<AtomicBool>,
+        stop: Arc<AtomicBool>,
+        listener: Option<JoinHandle<()>>,
+    }
+
+    impl Server {
+        pub(crate) fn start(path: &Path) -> io::Result<Self> {
+            let listener = UnixListener::bind(path)?;
+            let restricted = fs::set_permissions(path, Permissions::from_mode(0o600))
+                .and_then(|_| listener.set_nonblocking(true));
+            if let Err(err) = restricted {
+                let _ = fs::remove_file(path);
+                return Err(err);
+            }
+            let (send, queries) = mpsc::channel();
+            let pending = Arc::new(AtomicBool::new(false));
+            let stop = Arc::new(AtomicBool::new(false));
+
+            let (thread_pending, thread_stop) = (pending.clone(), stop.clone());
+            let handle = std::thread::Builder::new().name("gc_rs inspection".to_string()).spawn(move || {
+                // Accepting doesn't block, so the loop sees the stop flag
+                // even if nothing can connect any more
+                while !thread_stop.load(Ordering::Acquire) {
+                    let stream = match listener.accept() {
+                        Ok((stream, _)) => stream,
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(_) => {
+                            std::thread::sleep(POLL);
+                            continue;
+                        }
+                    };
+                    let mut query = String::new();
+                    let read = stream
+                        .set_nonblocking(false)
+                        .and_then(|_| stream.set_read_timeout(Some(TIMEOUT)))
+                        .and_then(|_| BufReader::new(&stream).read_line(&mut query));
+                    if read.is_err() {
+                        continue;
+                    }
+                    if send.send((query.trim().to_string(), stream)).is_err() {
+                        break;
+                    }
+                    thread_pending.store(true, Ordering::Release);
+                }
+            })?;
+
+            Ok(Server { path: path.to_path_buf(), queries, pending, stop, listener: Some(handle) })
+        }
+
+        pub(crate) fn pending(&self) -> bool {
+            self.pending.load(Ordering::Acquire)
+        }
+
+        pub(crate) fn answer_all(&self, state: &GcState) {
+            self.pending.store(false, Ordering::Release);
+            while let Ok((query, mut stream)) = self.queries.try_recv() {
+                // Nothing to do if the client has gone away
+                let _ = stream
+                    .set_write_timeout(Some(TIMEOUT))
+                    .and_then(|_| write_answer(state, &query, &mut stream));
+            }
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Release);
+            if let Some(handle) = self.listener.take() {
+                let _ = handle.join();
+            }
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn write_answer(state: &GcState, query: &str, stream: &mut UnixStream) -> io::Result<()> {
+        match query {
+            "stats" => writeln!(stream, "{:#?}", state.stats()),
+            "census" => {
+                for entry in state.census() {
+                    writeln!(stream, "{:>8} objects {:>10} bytes  {}", entry.count, entry.bytes, entry.type_name)?;
+                }
+                Ok(())
+            }
+            "snapshot" => state.snapshot().write_json(stream),
+            _ => writeln!(stream, "error: unknown query {:?} (expected stats, census or snapshot)", query),
+        }
+    }
+
+    impl GcState {
+        /// Starts answering queries on a Unix socket at `path`, replacing
+        /// any server already running for this heap. Fails if `path`
+        /// already exists.
+        ///
+        /// The socket is made accessible to its owner only, but it's
+        /// created with the process umask and restricted after, so another
+        /// user could connect in between. Put it in a directory only the
+        /// owner can use to rule that out.
+        pub fn start_inspection_server(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+            self.server = None;
+            self.server = Some(Server::start(path.as_ref())?);
+            Ok(())
+        }
+
+        /// Stops the server and removes its socket.
+        pub fn stop_inspection_server(&mut self) {
+            self.server = None;
+        }
+    }
+
+    pub fn start_inspection_server(path: impl AsRef<Path>) -> io::Result<()> {
+        GC_STATE.with(|state| state.borrow_mut().start_inspection_server(path))
+    }
+
+    pub fn stop_inspection_server() {
+        // Dropped outside the borrow, as joining the listener can take a
+        // moment
+        let server = GC_STATE.with(|state| state.borrow_mut().server.take());
+        drop(server);
+    }
+}
+
+#[cfg(unix)]
+pub(crate) use socket::Server;
+#[cfg(unix)]
+pub use socket::{start_inspection_server, stop_inspection_server};
+
+impl GcState {
+    pub(crate) fn queries_pending(&self) -> bool {
+        #[cfg(unix)]
+        return self.server.as_ref().is_some_and(Server::pending);
+
+        #[cfg(not(unix))]
+        return false;
+    }
+
+    /// Answers any inspection queries waiting for this heap.
+    pub fn answer_queries(&self) {
+        #[cfg(unix)]
+        if let Some(server) = &self.server {
+            server.answer_all(self);
+        }
+    }
+}
+
+/// A point where the inspection server may use this thread's heap. Queries
+/// are also answered on allocation, so this is only needed by threads that
+/// can go a long time without allocating.
+pub fn safepoint() {
+    GC_STATE.with(|state| {
+        let state = state.borrow();
+        if state.queries_pending() {
+            state.answer_queries();
+        }
+    });
+}
@@ -0,0 +1,325 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::gc_state::*;
+use crate::graph::*;
+use crate::json::{self, Value};
+
+// A copy of the heap that outlives it: it can be saved, loaded in another
+// process and compared with a later one. Objects are identified by the id
+// in their node header, so an object in two snapshots of the same heap has
+// the same id in both.
+
+const FORMAT_VERSION: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotObject {
+    pub id: u64,
+    pub type_name: String,
+    /// As `file:line:column`, if the `track-alloc-sites` feature is enabled
+    pub alloc_site: Option<String>,
+    /// Shallow size, as in `GcStats`
+    pub size: usize,
+    pub roots: usize,
+    /// Ids of the objects this one references
+    pub edges: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeapSnapshot {
+    /// In heap order, most recently allocated first
+    pub objects: Vec<SnapshotObject>,
+}
+
+impl HeapSnapshot {
+    pub fn new(state: &GcState) -> Self {
+        let graph = HeapGraph::new(state);
+        let objects = graph
+            .nodes
+            .iter()
+            .map(|node| SnapshotObject {
+                id: node.id,
+                type_name: node.type_name.to_string(),
+                alloc_site: node.alloc_site.map(|site| site.to_string()),
+                size: node.size,
+                roots: node.roots,
+                edges: node.edges.iter().map(|&to| graph.nodes[to].id).collect(),
+            })
+            .collect();
+        HeapSnapshot { objects }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&SnapshotObject> {
+        self.objects.iter().find(|object| object.id == id)
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.objects.iter().map(|object| object.size).sum()
+    }
+
+    /// Ids of the objects holding a handle to `id`.
+    pub fn referrers(&self, id: u64) -> Vec<u64> {
+        self.objects.iter().filter(|object| object.edges.contains(&id)).map(|object| object.id).collect()
+    }
+
+    /// One shortest chain of ids from a rooted object to `id`, root first,
+    /// like `GcState::retaining_path`. `None` if nothing rooted reaches it.
+    pub fn path_to_root(&self, id: u64) -> Option<Vec<u64>> {
+        let index: HashMap<u64, usize> = self.objects.iter().enumerate().map(|(i, o)| (o.id, i)).collect();
+        let target = *index.get(&id)?;
+
+        let mut parent: Vec<Option<usize>> = vec![None; self.objects.len()];
+        let mut seen = vec![false; self.objects.len()];
+        let mut queue = VecDeque::new();
+        for i in (0..self.objects.len()).filter(|&i| self.objects[i].roots > 0) {
+            seen[i] = true;
+            queue.push_back(i);
+        }
+
+        while let Some(i) = queue.pop_front() {
+            if i == target {
+                let mut path = Vec::new();
+                let mut curr = Some(i);
+                while let Some(i) = curr {
+                    path.push(self.objects[i].id);
+                    curr = parent[i];
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for to in self.objects[i].edges.iter().filter_map(|id| index.get(id)) {
+                if !seen[*to] {
+                    seen[*to] = true;
+                    parent[*to] = Some(i);
+                    queue.push_back(*to);
+                }
+            }
+        }
+        None
+    }
+
+    /// Writes the snapshot as JSON, one object per line.
+    pub fn write_json<W: Write>(&self, mut w: W) -> io::Result<()> {
+        write!(w, "{{\"gc_rs_heap_snapshot\":{},\"objects\":[", FORMAT_VERSION)?;
+        for (i, object) in self.objects.iter().enumerate() {
+            write!(w, "{}\n{{\"id\":{},\"type\":", if i > 0 { "," } else { "" }, object.id)?;
+            json::write_str(&mut w, &object.type_name)?;
+            write!(w, ",\"site\":")?;
+            match &object.alloc_site {
+                Some(site) => json::write_str(&mut w, site)?,
+                None => write!(w, "null")?,
+            }
+            write!(w, ",\"size\":{},\"roots\":{},\"edges\":", object.size, object.roots)?;
+            json::write_array(&mut w, &object.edges)?;
+            write!(w, "}}")?;
+        }
+        writeln!(w, "\n]}}")
+    }
+
+    /// Reads a snapshot written by `write_json`.
+    pub fn read_json<R: Read>(mut r: R) -> io::Result<Self> {
+        let mut s = String::new();
+        r.read_to_string(&mut s)?;
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+
+        let value = json::parse(&s).map_err(invalid)?;
+        match value.get("gc_rs_heap_snapshot").and_then(Value::as_u64) {
+            Some(FORMAT_VERSION) => {}
+            Some(v) => return Err(invalid(format!("unsupported snapshot version {}", v))),
+            None => return Err(invalid("not a gc_rs heap snapshot".to_string())),
+        }
+
+        let objects = value.get("objects").and_then(Value::as_array).ok_or_else(|| invalid("no objects".to_string()))?;
+        let objects = objects
+            .iter()
+            .map(|object| {
+                let field = |key: &str| object.get(key).ok_or_else(|| invalid(format!("object has no \"{}\"", key)));
+                let number = |key: &str| {
+                    field(key)?.as_u64().ok_or_else(|| invalid(format!("\"{}\" isn't a number", key)))
+                };
+                let edges = field("edges")?
+                    .as_array()
+                    .ok_or_else(|| invalid("\"edges\" isn't an array".to_string()))?
+                    .iter()
+                    .map(|edge| edge.as_u64().ok_or_else(|| invalid("edge isn't a number".to_string())))
+                    .collect::<io::Result<_>>()?;
+                Ok(SnapshotObject {
+                    id: number("id")?,
+                    type_name: field("type")?.as_str().ok_or_else(|| invalid("\"type\" isn't a string".to_string()))?.to_string(),
+                    alloc_site: field("site")?.as_str().map(str::to_string),
+                    size: number("size")? as usize,
+                    roots: number("roots")? as usize,
+                    edges,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(HeapSnapshot { objects })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_json(&mut w)?;
+        w.flush()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read_json(File::open(path)?)
+    }
+
+    /// What changed between this snapshot and a `later` one of the same
+    /// heap, per type and allocation site.
+    pub fn diff(&self, later: &HeapSnapshot) -> SnapshotDiff {
+        let mut groups: HashMap<(&str, Option<&str>), DiffEntry> = HashMap::new();
+        for (snapshot, is_later) in [(self, false), (later, true)] {
+            for object in &snapshot.objects {
+                let entry = groups
+                    .entry((object.type_name.as_str(), object.alloc_site.as_deref()))
+                    .or_insert_with(|| DiffEntry {
+                        type_name: object.type_name.clone(),
+                        alloc_site: object.alloc_site.clone(),
+                        ..DiffEntry::default()
+                    });
+                if is_later {
+                    entry.count_after += 1;
+                    entry.bytes_after += object.size;
+                } else {
+                    entry.count_before += 1;
+                    entry.bytes_before += object.size;
+                }
+            }
+        }
+
+        let before: HashSet<u64> = self.objects.iter().map(|object| object.id).collect();
+        let after: HashSet<u64> = later.objects.iter().map(|object| object.id).collect();
+        let mut new_objects: Vec<u64> = after.difference(&before).copied().collect();
+        let mut freed_objects: Vec<u64> = before.difference(&after).copied().collect();
+        new_objects.sort();
+        freed_objects.sort();
+
+        let mut entries: Vec<DiffEntry> = groups
+            .into_values()
+            .filter(|entry| entry.count_delta() != 0 || entry.bytes_delta() != 0)
+            .collect();
+        entries.sort_by(|a, b| {
+            b.bytes_delta()
+                .cmp(&a.bytes_delta())
+                .then(b.count_delta().cmp(&a.count_delta()))
+                .then(a.type_name.cmp(&b.type_name))
+                .then(a.alloc_site.cmp(&b.alloc_site))
+        });
+
+        SnapshotDiff {
+            objects_before: self.objects.len(),
+            objects_after: later.objects.len(),
+            bytes_before: self.total_bytes(),
+            bytes_after: later.total_bytes(),
+            entries,
+            new_objects,
+            freed_objects,
+        }
+    }
+}
+
+/// How one type (at one allocation site) changed between two snapshots.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiffEntry {
+    pub type_name: String,
+    pub alloc_site: Option<String>,
+    pub count_before: usize,
+    pub count_after: usize,
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+}
+
+impl DiffEntry {
+    pub fn count_delta(&self) -> isize {
+        self.count_after as isize - self.count_before as isize
+    }
+
+    pub fn bytes_delta(&self) -> isize {
+        self.bytes_after as isize - self.bytes_before as isize
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    pub objects_before: usize,
+    pub objects_after: usize,
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+    /// Every type and site whose count or bytes changed, most grown first
+    pub entries: Vec<DiffEntry>,
+    /// Ids only in the later snapshot
+    pub new_objects: Vec<u64>,
+    /// Ids only in the earlier snapshot
+    pub freed_objects: Vec<u64>,
+}
+
+impl SnapshotDiff {
+    /// The entries whose count or bytes grew, most grown first.
+    pub fn grown(&self) -> impl Iterator<Item = &DiffEntry> {
+        self.entries.iter().filter(|entry| entry.count_delta() > 0 || entry.bytes_delta() > 0)
+    }
+
+    pub fn shrunk(&self) -> impl Iterator<Item = &DiffEntry> {
+        self.entries.iter().rev().filter(|entry| entry.count_delta() < 0 || entry.bytes_delta() < 0)
+    }
+}
+
+fn write_entry(f: &mut fmt::Formatter<'_>, entry: &DiffEntry) -> fmt::Result {
+    write!(
+        f,
+        "\n  {:>+8} objects {:>+10} bytes  {}",
+        entry.count_delta(),
+        entry.bytes_delta(),
+        entry.type_name,
+    )?;
+    if let Some(site) = &entry.alloc_site {
+        write!(f, " @ {}", site)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} objects ({:+}), {} -> {} bytes ({:+})\n{} new, {} freed",
+            self.objects_before,
+            self.objects_after,
+            self.objects_after as isize - self.objects_before as isize,
+            self.bytes_before,
+            self.bytes_after,
+            self.bytes_after as isize - self.bytes_before as isize,
+            self.new_objects.len(),
+            self.freed_objects.len(),
+        )?;
+        if self.grown().next().is_some() {
+            write!(f, "\ngrew:")?;
+            for entry in self.grown() {
+                write_entry(f, entry)?;
+            }
+        }
+        if self.shrunk().next().is_some() {
+            write!(f, "\nshrank:")?;
+            for entry in self.shrunk() {
+                write_entry(f, entry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GcState {
+    pub fn snapshot(&self) -> HeapSnapshot {
+        HeapSnapshot::new(self)
+    }
+}
+
+/// Takes a snapshot of this thread's heap.
+pub fn snapshot() -> HeapSnapshot {
+    GC_STATE.with(|state| state.borrow().snapshot())
+}
@@ -0,0 +1,64 @@
+use std::cell::Cell;
+use std::time::Duration;
+
+use crate::gc_state::GC_STATE;
+
+/// A snapshot of the thread's heap counters. Everything here is kept up to
+/// date as objects are allocated, rooted and freed, so reading it is cheap.
+///
+/// Byte counts are shallow: the size of each `GcNode` (header and value),
+/// not anything the value owns on the ordinary heap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub live_objects: usize,
+    pub live_bytes: usize,
+    /// Number of live objects with at least one root
+    pub roots: usize,
+    pub bytes_allocated: usize,
+    pub collections: usize,
+    pub objects_freed: usize,
+    pub bytes_freed: usize,
+    pub last_pause: Duration,
+    pub max_pause: Duration,
+    pub total_gc_time: Duration,
+}
+
+impl GcStats {
+    /// The change since `earlier`. Running totals are differenced, while
+    /// the live counts and pause times are the values in `self`.
+    pub fn since(&self, earlier: &GcStats) -> GcStats {
+        GcStats {
+            bytes_allocated: self.bytes_allocated - earlier.bytes_allocated,
+            collections: self.collections - earlier.collections,
+            objects_freed: self.objects_freed - earlier.objects_freed,
+            bytes_freed: self.bytes_freed - earlier.bytes_freed,
+            total_gc_time: self.total_gc_time - earlier.total_gc_time,
+            ..*self
+        }
+    }
+}
+
+// Roots change in `Gc` handles without going through GC_STATE (which may be
+// borrowed at the time, e.g. when a value is dropped during a sweep), so
+// this one lives outside of it.
+thread_local!(static ROOTED_NODES: Cell<usize> = const { Cell::new(0) });
+
+pub(crate) fn rooted_nodes() -> usize {
+    ROOTED_NODES.with(|n| n.get())
+}
+
+pub(crate) fn add_rooted_node() {
+    ROOTED_NODES.with(|n| n.set(n.get() + 1));
+}
+
+pub(crate) fn sub_rooted_node() {
+    ROOTED_NODES.with(|n| n.set(n.get() - 1));
+}
+
+pub(crate) fn reset_rooted_nodes() {
+    ROOTED_NODES.with(|n| n.set(0));
+}
+
+pub fn stats() -> GcStats {
+    GC_STATE.with(|state| state.borrow().stats())
+}
@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::gc_state::*;
+use crate::json;
+use crate::log::{CollectionRecord, GcReason};
+
+// Collections as Chrome Trace Event Format "complete" events: one spanning
+// each whole collection (hooks included), with the mark and sweep nested
+// inside it. chrome://tracing and Perfetto both load the JSON this writes.
+
+// Timestamps are microseconds since the first time anything asked for one,
+// shared by every thread so their traces line up.
+static CLOCK_START: OnceLock<Instant> = OnceLock::new();
+
+fn clock_start() -> Instant {
+    *CLOCK_START.get_or_init(Instant::now)
+}
+
+/// Microseconds on the clock trace events are written with, for recording
+/// application spans that line up with the collections.
+pub fn trace_clock_us() -> f64 {
+    micros(Instant::now())
+}
+
+fn micros(at: Instant) -> f64 {
+    at.saturating_duration_since(clock_start()).as_secs_f64() * 1e6
+}
+
+// Trace viewers group events by thread, so every heap gets a small id
+static NEXT_TID: AtomicUsize = AtomicUsize::new(1);
+
+// Only the parts of a `CollectionRecord` the args show
+struct CollectionArgs {
+    number: usize,
+    reason: GcReason,
+    objects: (usize, usize),
+    bytes: (usize, usize),
+    roots: usize,
+}
+
+enum TraceEvent {
+    Collection { args: CollectionArgs, start: Instant, dur: Duration },
+    Phase { name: &'static str, collection: usize, start: Instant, dur: Duration },
+}
+
+pub(crate) struct TraceLog {
+    tid: usize,
+    events: Vec<TraceEvent>,
+}
+
+impl TraceLog {
+    fn new() -> Self {
+        // Make sure the clock starts before the first event
+        clock_start();
+        TraceLog { tid: NEXT_TID.fetch_add(1, Ordering::Relaxed), events: Vec::new() }
+    }
+
+    pub(crate) fn phase(&mut self, name: &'static str, collection: usize, start: Instant, dur: Duration) {
+        self.events.push(TraceEvent::Phase { name, collection, start, dur });
+    }
+
+    pub(crate) fn collection(&mut self, record: &CollectionRecord) {
+        let args = CollectionArgs {
+            number: record.number,
+            reason: record.reason,
+            objects: (record.before.live_objects, record.after.live_objects),
+            bytes: (record.before.live_bytes, record.after.live_bytes),
+            roots: record.roots_scanned,
+        };
+        self.events.push(TraceEvent::Collection { args, start: record.started, dur: record.started.elapsed() });
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let pid = std::process::id();
+        write!(w, "{{\"traceEvents\":[")?;
+        write!(w, "\n{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":", pid, self.tid)?;
+        let thread = std::thread::current();
+        json::write_str(w, &format!("gc_rs heap ({})", thread.name().unwrap_or("unnamed")))?;
+        write!(w, "}}}}")?;
+
+        for event in &self.events {
+            let (name, start, dur) = match event {
+                TraceEvent::Collection { start, dur, .. } => ("gc", *start, *dur),
+                TraceEvent::Phase { name, start, dur, .. } => (*name, *start, *dur),
+            };
+            write!(
+                w,
+                ",\n{{\"name\":\"{}\",\"cat\":\"gc\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":{},\"tid\":{},\"args\":",
+                name,
+                micros(start),
+                dur.as_secs_f64() * 1e6,
+                pid,
+                self.tid,
+            )?;
+            match event {
+                TraceEvent::Collection { args, .. } => write!(
+                    w,
+                    "{{\"collection\":{},\"reason\":\"{}\",\"objects_before\":{},\"objects_after\":{},\"bytes_before\":{},\"bytes_after\":{},\"roots\":{}}}}}",
+                    args.number,
+                    args.reason,
+                    args.objects.0,
+                    args.objects.1,
+                    args.bytes.0,
+                    args.bytes.1,
+                    args.roots,
+                )?,
+                TraceEvent::Phase { collection, .. } => write!(w, "{{\"collection\":{}}}}}", collection)?,
+            }
+        }
+        writeln!(w, "\n],\"displayTimeUnit\":\"ms\"}}")
+    }
+}
+
+impl GcState {
+    /// Starts or stops recording collections as trace events. Stopping
+    /// drops anything not yet written.
+    pub fn set_trace_events(&mut self, record: bool) {
+        match (record, &self.trace_events) {
+            (true, None) => self.trace_events = Some(TraceLog::new()),
+            (false, _) => self.trace_events = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    /// Writes the events recorded so far as a Chrome trace, then forgets
+    /// them, so each write only has the collections since the last.
+    pub fn write_trace_events<W: Write>(&mut self, mut w: W) -> io::Result<()> {
+        let Some(trace) = &mut self.trace_events else {
+            return writeln!(w, "{{\"traceEvents\":[]}}");
+        };
+        trace.write(&mut w)?;
+        trace.events.clear();
+        Ok(())
+    }
+}
+
+pub fn set_trace_events(record: bool) {
+    GC_STATE.with(|state| state.borrow_mut().set_trace_events(record));
+}
+
+/// Writes this thread's recorded collections to `path` as a trace
+/// `chrome://tracing` or Perfetto can open.
+pub fn flush_trace_events(path: impl AsRef<Path>) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    GC_STATE.with(|state| state.borrow_mut().write_trace_events(&mut w))?;
+    w.flush()
+}
@@ -1,31 +1,308 @@
+#[cfg(feature = "nightly")]
+use std::alloc::Allocator;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, LinkedList, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::marker::PhantomData;
+use std::num::{
+    NonZeroI128, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize, NonZeroU128, NonZeroU16, NonZeroU32,
+    NonZeroU64, NonZeroU8, NonZeroUsize,
+};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-pub trait Trace {
-    // Trace calls trace on all Gc children and marks them
-    fn trace(&self);
+use crate::gc::GcEdge;
 
-    fn root_children(&self);
+// Everything the collector does with the object graph - marking, rooting
+// and derooting the handles inside a new object, enumerating edges for the
+// inspection tools - is a `Tracer` that `Trace::trace` hands every `Gc` to.
+// So a type only has to say where its handles are, once.
+//
+// Impls written for the old five method trait (`trace`, `root_children`,
+// `deroot_children`, `root`, `deroot`) become a single `trace` that calls
+// `trace(tracer)` on what the old `trace` did. `root` and `deroot` are now
+// methods on `Gc` only, and `root_children`/`deroot_children` are the free
+// functions below. Derived impls and the `empty_trace!`/`iter_trace!`
+// macros generate the new form, so those need no changes.
+//
+// `Trace` is unsafe to implement: an impl that misses a handle lets a
+// collection free an object that's still reachable through it. Derive it,
+// or derive `NoGc` for types with no handles in them, and nothing needs to
+// be written in an `unsafe` block.
 
-    fn deroot_children(&self);
+/// Types the collector can find `Gc` handles in.
+///
+/// # Safety
+///
+/// `trace` must call `trace` on every value inside `self` that can hold a
+/// `Gc`, and nothing else, and must do the same on every call for as long
+/// as the value is in the heap. A handle it misses isn't marked, so its
+/// object can be freed while still in use.
+///
+/// `#[derive(Trace)]` generates a correct impl. Fields it can't trace can
+/// be marked with `#[gc(...)]`:
+///
+/// - `#[gc(skip)]` leaves the field out, so it needn't be `Trace`. This
+///   asserts the field holds no `Gc`, which the derive can't check.
+/// - `#[gc(trace_with = "path")]` traces the field by calling
+///   `path(&field, tracer)`, which takes on the contract of `trace` above,
+///   so must be an `unsafe fn`.
+///
+/// On the type itself:
+///
+/// - `#[gc(bound = "T: Trace, ...")]` replaces the bounds the derive puts
+///   on its impl, which are otherwise that every traced field using a type
+///   parameter, and the parameter, is `Trace`.
+/// - `#[gc(finalize)]` runs the type's `Finalize` impl before it's freed.
+/// - `#[gc(unsafe_drop)]` allows a `Drop` impl, which is otherwise an
+///   error (see `Finalize`). This asserts `drop` doesn't use any `Gc`.
+///
+/// ```
+/// use gc_rs::{Gc, Trace, Tracer};
+///
+/// struct Connection;
+///
+/// // From another crate, so it can't implement `Trace` here
+/// struct Registry(Vec<Gc<i32>>);
+///
+/// unsafe fn trace_registry(registry: &Registry, tracer: &mut dyn Tracer) {
+///     registry.0.trace(tracer);
+/// }
+///
+/// #[derive(Trace)]
+/// struct Session {
+///     #[gc(skip)]
+///     connection: Connection,
+///     #[gc(trace_with = "trace_registry")]
+///     registry: Registry,
+///     user: Gc<String>,
+/// }
+/// ```
+///
+/// A safe fn is rejected, as whoever changes it wouldn't know it has to keep
+/// to that contract:
+///
+/// ```compile_fail
+/// use gc_rs::{Gc, Trace, Tracer};
+///
+/// struct Registry(Vec<Gc<i32>>);
+///
+/// fn trace_registry(registry: &Registry, tracer: &mut dyn Tracer) {
+///     registry.0.trace(tracer);
+/// }
+///
+/// #[derive(Trace)]
+/// struct Session {
+///     #[gc(trace_with = "trace_registry")]
+///     registry: Registry,
+/// }
+/// ```
+///
+/// It only needs to be written by hand for types the derive can't see into:
+///
+/// ```
+/// use gc_rs::{Gc, Trace, Tracer};
+///
+/// struct Pair(Gc<i32>, Gc<i32>);
+///
+/// unsafe impl Trace for Pair {
+///     fn trace(&self, tracer: &mut dyn Tracer) {
+///         self.0.trace(tracer);
+///         self.1.trace(tracer);
+///     }
+/// }
+/// ```
+///
+/// Implementing it without `unsafe` doesn't compile:
+///
+/// ```compile_fail
+/// use gc_rs::{Gc, Trace, Tracer};
+///
+/// struct Pair(Gc<i32>, Gc<i32>);
+///
+/// impl Trace for Pair {
+///     fn trace(&self, tracer: &mut dyn Tracer) {
+///         self.0.trace(tracer);
+///     }
+/// }
+/// ```
+pub unsafe trait Trace {
+    /// Calls `trace` on everything inside `self` that can hold a `Gc`,
+    /// which ends in `tracer.edge` for each handle.
+    fn trace(&self, tracer: &mut dyn Tracer);
 
-    fn root(&self);
+    /// Called on an unreachable object before the sweep frees it. Does
+    /// nothing unless overridden, as `#[gc(finalize)]` does to call
+    /// `Finalize::finalize`. Only called if `finalizes` is overridden too.
+    #[inline]
+    fn finalize_glue(&self) {}
+
+    /// Whether the type overrides `finalize_glue`. Recorded in each
+    /// object's header when it's allocated, so collections that free
+    /// nothing that finalizes don't have to look for finalizers.
+    #[doc(hidden)]
+    #[inline]
+    fn finalizes() -> bool
+    where
+        Self: Sized,
+    {
+        false
+    }
+}
+
+/// Cleanup for a traced type, in place of `Drop`.
+///
+/// The sweep frees unreachable objects one at a time, so a `drop` that
+/// uses a `Gc` could find its object already gone. `#[derive(Trace)]`
+/// rejects a `Drop` impl on the type for that reason:
+///
+/// ```compile_fail
+/// use gc_rs::{Gc, Trace};
+///
+/// #[derive(Trace)]
+/// struct Node {
+///     next: Option<Gc<Node>>,
+/// }
+///
+/// impl Drop for Node {
+///     fn drop(&mut self) {
+///         if let Some(next) = &self.next {
+///             let _ = next.next.is_some();
+///         }
+///     }
+/// }
+/// ```
+///
+/// `finalize` runs first instead, on everything the collection is about to
+/// free, while all of it is still there. Derive with `#[gc(finalize)]` to
+/// have it called:
+///
+/// ```
+/// use gc_rs::{Finalize, Gc, Trace};
+///
+/// #[derive(Trace)]
+/// #[gc(finalize)]
+/// struct Node {
+///     next: Option<Gc<Node>>,
+/// }
+///
+/// impl Finalize for Node {
+///     fn finalize(&self) {
+///         if let Some(next) = &self.next {
+///             let _ = next.next.is_some();
+///         }
+///     }
+/// }
+/// ```
+///
+/// Finalizers run between marking and sweeping, with the heap not
+/// borrowed, so they can allocate; what they allocate survives the
+/// collection. Cloning a handle keeps its object alive, and a kept object
+/// is finalized again when it next becomes unreachable. When collecting
+/// through `GcState::collect_garbage`, the heap is already borrowed, so a
+/// finalizer that allocates panics.
+///
+/// If a finalizer panics, the collection is abandoned without freeing
+/// anything, and the objects are finalized again by the next one.
+pub trait Finalize {
+    fn finalize(&self);
+}
+
+/// Types that contain no `Gc`, so tracing them does nothing. It can't be
+/// implemented by hand, only with `#[derive(NoGc)]`, which checks every
+/// field is `NoGc` too:
+///
+/// ```
+/// use gc_rs::NoGc;
+///
+/// #[derive(NoGc)]
+/// struct Point {
+///     x: i32,
+///     y: i32,
+///     label: Option<String>,
+/// }
+/// ```
+///
+/// A field holding a handle is an error:
+///
+/// ```compile_fail
+/// use gc_rs::{Gc, NoGc};
+///
+/// #[derive(NoGc)]
+/// struct Node {
+///     value: i32,
+///     next: Option<Gc<i32>>,
+/// }
+/// ```
+///
+/// As is implementing it by hand, which would let the derive's check be
+/// fooled:
+///
+/// ```compile_fail
+/// use gc_rs::{Gc, NoGc};
+///
+/// struct Node {
+///     next: Gc<i32>,
+/// }
+///
+/// impl NoGc for Node {}
+/// ```
+pub trait NoGc: NoGcImpl {}
+
+impl<T: NoGcImpl + ?Sized> NoGc for T {}
+
+/// What `#[derive(NoGc)]` implements to get `NoGc`, so that a manual
+/// `NoGc` impl needs an `unsafe` one of this too.
+///
+/// # Safety
+/// The type must contain no `Gc`.
+#[doc(hidden)]
+pub unsafe trait NoGcImpl {}
+
+pub trait Tracer {
+    /// Called for each `Gc` handle directly inside the value being traced.
+    fn edge(&mut self, edge: GcEdge<'_>);
+}
 
-    fn deroot(&self);
+struct Rooter;
+
+impl Tracer for Rooter {
+    fn edge(&mut self, edge: GcEdge<'_>) {
+        edge.root();
+    }
+}
+
+struct Derooter;
+
+impl Tracer for Derooter {
+    fn edge(&mut self, edge: GcEdge<'_>) {
+        edge.deroot();
+    }
+}
+
+/// Roots every handle directly inside `value`, for when it's moved out of
+/// the heap.
+pub fn root_children<T: Trace + ?Sized>(value: &T) {
+    value.trace(&mut Rooter);
+}
+
+/// Deroots every handle directly inside `value`, for when it's moved into
+/// the heap. `Gc::new` does this for the value it's given.
+///
+/// # Safety
+///
+/// As with `Gc::deroot`, `value` must be somewhere it'll be traced from
+/// for as long as the handles in it are used.
+pub unsafe fn deroot_children<T: Trace + ?Sized>(value: &T) {
+    value.trace(&mut Derooter);
 }
 
 #[macro_export]
 macro_rules! empty_trace {
     () => {
         #[inline]
-        fn trace(&self) {}
-        #[inline]
-        fn root_children(&self) {}
-        #[inline]
-        fn deroot_children(&self) {}
-        #[inline]
-        fn root(&self) {}
-        #[inline]
-        fn deroot(&self) {}
+        fn trace(&self, _tracer: &mut dyn $crate::traits::Tracer) {}
     };
 }
 
@@ -33,48 +310,41 @@ macro_rules! empty_trace {
 macro_rules! iter_trace {
     () => {
         #[inline]
-        fn trace(&self) {
-            for item in self {
-                item.trace();
-            }
-        }
-
-        #[inline]
-        fn root_children(&self) {
-            for item in self {
-                item.root_children();
-            }
-        }
-
-        #[inline]
-        fn deroot_children(&self) {
+        fn trace(&self, tracer: &mut dyn $crate::traits::Tracer) {
             for item in self {
-                item.deroot_children();
+                $crate::traits::Trace::trace(item, tracer);
             }
         }
-
-        #[inline]
-        fn root(&self) {}
-
-        #[inline]
-        fn deroot(&self) {}
     };
 }
 
 macro_rules! simple_empty_trace {
     ($($T:ty),*) => {
         $(
-            impl Trace for $T { empty_trace!(); }
+            unsafe impl Trace for $T { empty_trace!(); }
+            unsafe impl NoGcImpl for $T {}
         )*
     }
 }
 
+// Collections that trace each of their items. With the `nightly` feature
+// these are generic over the allocator too.
 macro_rules! simple_iter_trace {
-    ($($T:ty),*) => {
+    ($($T:ident<X $(, A: $bound:path)?>),*) => {
         $(
-            impl<X: Trace> Trace for $T {
+            #[cfg(not(feature = "nightly"))]
+            unsafe impl<X: Trace> Trace for $T<X> {
+                iter_trace!();
+            }
+            #[cfg(not(feature = "nightly"))]
+            unsafe impl<X: NoGc> NoGcImpl for $T<X> {}
+
+            #[cfg(feature = "nightly")]
+            unsafe impl<X: Trace, A: Allocator $(+ $bound)?> Trace for $T<X, A> {
                 iter_trace!();
             }
+            #[cfg(feature = "nightly")]
+            unsafe impl<X: NoGc, A: Allocator $(+ $bound)?> NoGcImpl for $T<X, A> {}
         )*
     }
 }
@@ -97,41 +367,205 @@ simple_empty_trace![
     f32,
     f64,
     char,
+    str,
+    &'static str,
     String,
-    Box<str>,
-    Rc<str>
+    Duration,
+    Instant,
+    PathBuf,
+    Path,
+    OsString,
+    OsStr,
+    NonZeroU8,
+    NonZeroU16,
+    NonZeroU32,
+    NonZeroU64,
+    NonZeroU128,
+    NonZeroUsize,
+    NonZeroI8,
+    NonZeroI16,
+    NonZeroI32,
+    NonZeroI64,
+    NonZeroI128,
+    NonZeroIsize
 ];
 
 simple_iter_trace![
     Vec<X>,
-    std::collections::LinkedList<X>
+    LinkedList<X>,
+    VecDeque<X>,
+    BinaryHeap<X>,
+    BTreeSet<X, A: Clone>
 ];
 
-impl<T, X: Trace> Trace for std::collections::HashMap<T, X> {
+unsafe impl<K: Trace, V: Trace, S> Trace for HashMap<K, V, S> {
     #[inline]
-    fn trace(&self) {
-        for item in self.values() {
-            item.trace();
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        for (key, value) in self {
+            key.trace(tracer);
+            value.trace(tracer);
         }
     }
+}
+
+unsafe impl<K: NoGc, V: NoGc, S> NoGcImpl for HashMap<K, V, S> {}
+
+unsafe impl<X: Trace, S> Trace for HashSet<X, S> {
+    iter_trace!();
+}
 
+unsafe impl<X: NoGc, S> NoGcImpl for HashSet<X, S> {}
+
+#[cfg(not(feature = "nightly"))]
+unsafe impl<K: Trace, V: Trace> Trace for BTreeMap<K, V> {
     #[inline]
-    fn root_children(&self) {
-        for item in self.values() {
-            item.root_children();
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        for (key, value) in self {
+            key.trace(tracer);
+            value.trace(tracer);
         }
     }
+}
+
+#[cfg(not(feature = "nightly"))]
+unsafe impl<K: NoGc, V: NoGc> NoGcImpl for BTreeMap<K, V> {}
 
+#[cfg(feature = "nightly")]
+unsafe impl<K: Trace, V: Trace, A: Allocator + Clone> Trace for BTreeMap<K, V, A> {
     #[inline]
-    fn deroot_children(&self) {
-        for item in self.values() {
-            item.deroot_children();
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        for (key, value) in self {
+            key.trace(tracer);
+            value.trace(tracer);
         }
     }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl<K: NoGc, V: NoGc, A: Allocator + Clone> NoGcImpl for BTreeMap<K, V, A> {}
+
+unsafe impl<X: Trace> Trace for [X] {
+    iter_trace!();
+}
+
+unsafe impl<X: NoGc> NoGcImpl for [X] {}
+
+unsafe impl<X: Trace, const N: usize> Trace for [X; N] {
+    iter_trace!();
+}
+
+unsafe impl<X: NoGc, const N: usize> NoGcImpl for [X; N] {}
+
+#[cfg(not(feature = "nightly"))]
+unsafe impl<T: Trace + ?Sized> Trace for Box<T> {
+    #[inline]
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        (**self).trace(tracer);
+    }
+}
+
+#[cfg(not(feature = "nightly"))]
+unsafe impl<T: NoGc + ?Sized> NoGcImpl for Box<T> {}
+
+#[cfg(feature = "nightly")]
+unsafe impl<T: Trace + ?Sized, A: Allocator> Trace for Box<T, A> {
+    #[inline]
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        (**self).trace(tracer);
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl<T: NoGc + ?Sized, A: Allocator> NoGcImpl for Box<T, A> {}
+
+// Passes edges on as shared, which rooting and derooting leave alone
+struct SharedTracer<'a>(&'a mut dyn Tracer);
+
+impl Tracer for SharedTracer<'_> {
+    fn edge(&mut self, edge: GcEdge<'_>) {
+        self.0.edge(edge.into_shared());
+    }
+}
 
+// An `Rc` in the heap can have clones outside it, which need its handles
+// rooted, and moving one clone into the heap says nothing about the rest.
+// So its handles stay rooted wherever it is: it's traced for marking and
+// the inspection tools, but never rooted or derooted. A cycle back through
+// an `Rc` therefore isn't collected.
+#[cfg(not(feature = "nightly"))]
+unsafe impl<T: Trace + ?Sized> Trace for Rc<T> {
     #[inline]
-    fn root(&self) {}
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        (**self).trace(&mut SharedTracer(tracer));
+    }
+}
 
+#[cfg(not(feature = "nightly"))]
+unsafe impl<T: NoGc + ?Sized> NoGcImpl for Rc<T> {}
+
+#[cfg(feature = "nightly")]
+unsafe impl<T: Trace + ?Sized, A: Allocator> Trace for Rc<T, A> {
     #[inline]
-    fn deroot(&self) {}
+    fn trace(&self, tracer: &mut dyn Tracer) {
+        (**self).trace(&mut SharedTracer(tracer));
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl<T: NoGc + ?Sized, A: Allocator> NoGcImpl for Rc<T, A> {}
+
+// A `Gc` can't be `Copy`, so nothing in a `Cell` can be traced without
+// taking it out. `NoGc` makes sure there's nothing to trace.
+unsafe impl<T: Copy + NoGc> Trace for Cell<T> {
+    empty_trace!();
+}
+
+unsafe impl<T: Copy + NoGc> NoGcImpl for Cell<T> {}
+
+// A `RefMut` can be held across an allocation that collects, and reading
+// the value through `as_ptr` while it's alive would alias it, so only
+// values with nothing to trace are allowed. Handles that change go behind
+// `Gc::borrow_mut` instead.
+unsafe impl<T: NoGc + ?Sized> Trace for RefCell<T> {
+    empty_trace!();
+}
+
+unsafe impl<T: NoGc + ?Sized> NoGcImpl for RefCell<T> {}
+
+unsafe impl<T: ?Sized> Trace for PhantomData<T> {
+    empty_trace!();
+}
+
+unsafe impl<T: ?Sized> NoGcImpl for PhantomData<T> {}
+
+macro_rules! tuple_trace {
+    ($(($($T:ident),+)),*) => {
+        $(
+            unsafe impl<$($T: Trace),+> Trace for ($($T,)+) {
+                #[inline]
+                #[allow(non_snake_case)]
+                fn trace(&self, tracer: &mut dyn Tracer) {
+                    let ($($T,)+) = self;
+                    $($T.trace(tracer);)+
+                }
+            }
+
+            unsafe impl<$($T: NoGc),+> NoGcImpl for ($($T,)+) {}
+        )*
+    }
 }
+
+tuple_trace![
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+    (A, B, C, D, E, F),
+    (A, B, C, D, E, F, G),
+    (A, B, C, D, E, F, G, H),
+    (A, B, C, D, E, F, G, H, I),
+    (A, B, C, D, E, F, G, H, I, J),
+    (A, B, C, D, E, F, G, H, I, J, K),
+    (A, B, C, D, E, F, G, H, I, J, K, L)
+];
@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::gc_state::*;
+use crate::graph::*;
+use crate::stats::rooted_nodes;
+
+// With the `verify-handles` feature, every `Gc` handle reports when its
+// root flag is set and cleared, which gives a count of rooted handles per
+// node that is kept apart from the count in `GcData`. Deroots that
+// `GcData::sub_roots` ignores because the count is already zero are always
+// kept, as they would otherwise go unnoticed.
+mod handles {
+    use std::cell::RefCell;
+    #[cfg(feature = "verify-handles")]
+    use std::collections::HashMap;
+
+    thread_local! {
+        #[cfg(feature = "verify-handles")]
+        pub(super) static ROOTED_HANDLES: RefCell<HashMap<*const (), usize>> =
+            RefCell::new(HashMap::new());
+        pub(super) static SATURATED_DEROOTS: RefCell<Vec<(*const (), &'static str)>> =
+            const { RefCell::new(Vec::new()) };
+    }
+}
+
+#[cfg(feature = "verify-handles")]
+pub(crate) fn handle_rooted(addr: *const ()) {
+    handles::ROOTED_HANDLES.with(|h| *h.borrow_mut().entry(addr).or_default() += 1);
+}
+
+#[cfg(feature = "verify-handles")]
+pub(crate) fn handle_derooted(addr: *const ()) {
+    handles::ROOTED_HANDLES.with(|h| {
+        let mut h = h.borrow_mut();
+        if let Some(count) = h.get_mut(&addr) {
+            *count -= 1;
+            if *count == 0 {
+                h.remove(&addr);
+            }
+        }
+    });
+}
+
+pub(crate) fn note_saturated_deroot(addr: *const (), type_name: &'static str) {
+    handles::SATURATED_DEROOTS.with(|d| d.borrow_mut().push((addr, type_name)));
+}
+
+#[cfg(not(feature = "verify-handles"))]
+pub(crate) fn handle_rooted(_addr: *const ()) {}
+
+#[cfg(not(feature = "verify-handles"))]
+pub(crate) fn handle_derooted(_addr: *const ()) {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// The root count in the node header doesn't match the number of live
+    /// rooted handles (`verify-handles` feature only)
+    RootCountMismatch { type_name: &'static str, addr: *const (), recorded: usize, handles: usize },
+    /// A handle was derooted when its node had no roots left
+    SaturatedDeroot { type_name: &'static str, addr: *const () },
+    /// A node is still marked outside of a collection
+    MarkLeftSet { type_name: &'static str, addr: *const () },
+    /// A handle inside an object points at something that isn't on the list
+    DanglingEdge { holder_type: &'static str, holder: *const (), target: *const () },
+    /// Rooted handles point at something that isn't on the list
+    /// (`verify-handles` feature only)
+    DanglingHandle { target: *const (), handles: usize },
+    /// The number of nodes on the list doesn't match the stats
+    LiveCountMismatch { listed: usize, counted: usize },
+    RootedCountMismatch { listed: usize, counted: usize },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::RootCountMismatch { type_name, addr, recorded, handles } => write!(
+                f,
+                "{} @ {:p} has {} roots recorded but {} rooted handles",
+                type_name, addr, recorded, handles
+            ),
+            Violation::SaturatedDeroot { type_name, addr } => {
+                write!(f, "{} @ {:p} was derooted with no roots left", type_name, addr)
+            }
+            Violation::MarkLeftSet { type_name, addr } => {
+                write!(f, "{} @ {:p} is still marked", type_name, addr)
+            }
+            Violation::DanglingEdge { holder_type, holder, target } => write!(
+                f,
+                "{} @ {:p} holds a handle to {:p}, which isn't on the heap",
+                holder_type, holder, target
+            ),
+            Violation::DanglingHandle { target, handles } => write!(
+                f,
+                "{} rooted handles point to {:p}, which isn't on the heap",
+                handles, target
+            ),
+            Violation::LiveCountMismatch { listed, counted } => write!(
+                f,
+                "{} nodes are on the list but {} are counted as live",
+                listed, counted
+            ),
+            Violation::RootedCountMismatch { listed, counted } => write!(
+                f,
+                "{} nodes on the list are rooted but {} are counted as rooted",
+                listed, counted
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapVerifyError {
+    pub violations: Vec<Violation>,
+}
+
+impl fmt::Display for HeapVerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} heap invariant violation(s):", self.violations.len())?;
+        for violation in &self.violations {
+            write!(f, "\n  {}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for HeapVerifyError {}
+
+/// A rooted handle stored inside another object, as happens when one is
+/// assigned through `borrow_mut`. The target can't be freed while the
+/// holder is alive, even in a cycle, but the heap is otherwise sound, so
+/// this is a leak rather than a `Violation`. Handles in an `Rc` are left
+/// rooted, so aren't counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakWarning {
+    pub holder_type: &'static str,
+    pub holder: *const (),
+    pub target_type: &'static str,
+    pub target: *const (),
+}
+
+impl fmt::Display for LeakWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} @ {:p} holds a rooted handle to {} @ {:p}",
+            self.holder_type, self.holder, self.target_type, self.target
+        )
+    }
+}
+
+impl GcState {
+    /// Checks the heap for the kinds of corruption a bad `Trace` impl
+    /// causes. Must not be called part way through a collection, since
+    /// marks are expected then.
+    ///
+    /// Saturated deroots are only reported by the first check after they
+    /// happen.
+    pub fn verify(&self) -> Result<(), HeapVerifyError> {
+        let mut violations = Vec::new();
+
+        let mut addrs = HashSet::new();
+        let mut listed = 0;
+        let mut rooted = 0;
+        for node in self.nodes() {
+            addrs.insert(node_addr(node));
+            listed += 1;
+            if node.data.get().is_root() {
+                rooted += 1;
+            }
+        }
+
+        if listed != self.stats().live_objects {
+            violations.push(Violation::LiveCountMismatch { listed, counted: self.stats().live_objects });
+        }
+        if rooted != rooted_nodes() {
+            violations.push(Violation::RootedCountMismatch { listed: rooted, counted: rooted_nodes() });
+        }
+
+        for node in self.nodes() {
+            let addr = node_addr(node);
+            if node.data.get().is_marked() {
+                violations.push(Violation::MarkLeftSet { type_name: node.type_info.name(), addr });
+            }
+            for edge in edges_of(node) {
+                if !addrs.contains(&edge.addr) {
+                    violations.push(Violation::DanglingEdge {
+                        holder_type: node.type_info.name(),
+                        holder: addr,
+                        target: edge.addr,
+                    });
+                }
+            }
+        }
+
+        #[cfg(feature = "verify-handles")]
+        handles::ROOTED_HANDLES.with(|h| {
+            let h = h.borrow();
+            for node in self.nodes() {
+                let addr = node_addr(node);
+                let recorded = node.data.get().get_roots();
+                let handles = h.get(&addr).copied().unwrap_or(0);
+                if recorded != handles {
+                    violations.push(Violation::RootCountMismatch {
+                        type_name: node.type_info.name(),
+                        addr,
+                        recorded,
+                        handles,
+                    });
+                }
+            }
+
+            let mut dangling: Vec<_> = h.iter().filter(|(addr, _)| !addrs.contains(*addr)).collect();
+            dangling.sort();
+            for (&target, &handles) in dangling {
+                violations.push(Violation::DanglingHandle { target, handles });
+            }
+        });
+
+        handles::SATURATED_DEROOTS.with(|d| {
+            for (addr, type_name) in d.borrow_mut().drain(..) {
+                violations.push(Violation::SaturatedDeroot { type_name, addr });
+            }
+        });
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(HeapVerifyError { violations })
+        }
+    }
+
+    /// Whether to verify the heap at the end of every collection, panicking
+    /// on any violation.
+    pub fn set_verify_after_gc(&mut self, verify: bool) {
+        self.verify_after_gc = verify;
+    }
+
+    /// Finds rooted handles stored inside other objects, which keep their
+    /// targets alive for as long as the holder is.
+    pub fn leak_warnings(&self) -> Vec<LeakWarning> {
+        let types: HashMap<_, _> = self.nodes().map(|node| (node_addr(node), node.type_info.name())).collect();
+        let mut warnings = Vec::new();
+        for node in self.nodes() {
+            for edge in edges_of(node) {
+                if let Some(&target_type) = types.get(&edge.addr) {
+                    if edge.rooted && !edge.shared {
+                        warnings.push(LeakWarning {
+                            holder_type: node.type_info.name(),
+                            holder: node_addr(node),
+                            target_type,
+                            target: edge.addr,
+                        });
+                    }
+                }
+            }
+        }
+        warnings
+    }
+}
+
+pub fn verify_heap() -> Result<(), HeapVerifyError> {
+    GC_STATE.with(|state| state.borrow().verify())
+}
+
+pub fn leak_warnings() -> Vec<LeakWarning> {
+    GC_STATE.with(|state| state.borrow().leak_warnings())
+}
+
+pub fn set_verify_after_gc(verify: bool) {
+    GC_STATE.with(|state| state.borrow_mut().set_verify_after_gc(verify));
+}
@@ -1,38 +1,207 @@
 use proc_macro2::TokenStream;
-use quote::quote;
-use synstructure::{decl_derive, Structure};
+use quote::{quote, quote_spanned};
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{parse_quote, Attribute, Lit, Meta, NestedMeta, Token, WherePredicate};
+use synstructure::{decl_derive, AddBounds, BindingInfo, Structure};
 
-fn trace_derive(s: Structure) -> proc_macro2::TokenStream {
-    let trace_body = s.each(|bi| quote! {
-        ::gc_rs::Trace::trace(#bi);
-    });
-    let root_body = s.each(|bi| quote! {
-        ::gc_rs::Trace::root(#bi);
-        ::gc_rs::Trace::root_children(#bi);
+// How `#[derive(Trace)]` handles one field, from its `#[gc(...)]` attribute
+enum FieldMode {
+    Trace,
+    // Asserted to hold no `Gc`, so it doesn't need to be `Trace`
+    Skip,
+    // Traced by calling the function at this path
+    TraceWith(syn::Path),
+}
+
+// The `name` or `name = "value"` items inside every `#[gc(...)]`
+fn gc_attrs(attrs: &[Attribute]) -> syn::Result<Vec<Meta>> {
+    let mut metas = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("gc")) {
+        match attr.parse_meta()? {
+            Meta::List(list) => {
+                for nested in list.nested {
+                    match nested {
+                        NestedMeta::Meta(meta) => metas.push(meta),
+                        NestedMeta::Lit(lit) => return Err(syn::Error::new_spanned(lit, "expected a gc option")),
+                    }
+                }
+            }
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[gc(...)]")),
+        }
+    }
+    Ok(metas)
+}
+
+fn string_value(meta: &Meta) -> syn::Result<syn::LitStr> {
+    match meta {
+        Meta::NameValue(nv) => match &nv.lit {
+            Lit::Str(s) => Ok(s.clone()),
+            lit => Err(syn::Error::new_spanned(lit, "expected a string")),
+        },
+        _ => Err(syn::Error::new_spanned(meta, "expected `name = \"...\"`")),
+    }
+}
+
+fn field_mode(field: &syn::Field) -> syn::Result<FieldMode> {
+    let mut mode = FieldMode::Trace;
+    for meta in gc_attrs(&field.attrs)? {
+        if !matches!(mode, FieldMode::Trace) {
+            return Err(syn::Error::new_spanned(meta, "a field can only have one of `skip` and `trace_with`"));
+        }
+        mode = match &meta {
+            Meta::Path(path) if path.is_ident("skip") => FieldMode::Skip,
+            meta if meta.path().is_ident("trace_with") => FieldMode::TraceWith(string_value(meta)?.parse()?),
+            meta => return Err(syn::Error::new_spanned(meta, "unknown gc field option")),
+        };
+    }
+    Ok(mode)
+}
+
+// The options from `#[gc(...)]` on the type itself
+#[derive(Default)]
+struct ContainerOptions {
+    // Replaces the derived where-clause
+    bound: Option<Vec<WherePredicate>>,
+    // Allows a `Drop` impl, which the caller vouches doesn't use any `Gc`
+    unsafe_drop: bool,
+    // Runs the type's `Finalize` impl before it's freed
+    finalize: bool,
+}
+
+fn container_options(attrs: &[Attribute]) -> syn::Result<ContainerOptions> {
+    let mut options = ContainerOptions::default();
+    for meta in gc_attrs(attrs)? {
+        match &meta {
+            Meta::Path(path) if path.is_ident("unsafe_drop") => options.unsafe_drop = true,
+            Meta::Path(path) if path.is_ident("finalize") => options.finalize = true,
+            meta if meta.path().is_ident("bound") => {
+                let predicates = string_value(meta)?
+                    .parse_with(Punctuated::<WherePredicate, Token![,]>::parse_terminated)?;
+                options.bound.get_or_insert_with(Vec::new).extend(predicates);
+            }
+            meta => return Err(syn::Error::new_spanned(meta, "unknown gc option")),
+        }
+    }
+    Ok(options)
+}
+
+// Fails to compile if the type implements `Drop`, as the blanket impl for
+// `Drop` types would then conflict with the one for the type. A `Drop` impl
+// could use a `Gc` whose object the sweep has already freed.
+fn forbid_drop(s: &Structure) -> TokenStream {
+    let name = &s.ast().ident;
+    let (impl_generics, ty_generics, where_clause) = s.ast().generics.split_for_impl();
+    quote! {
+        const _: () = {
+            trait TracedTypesMustNotImplementDropUseFinalizeInstead {}
+            #[allow(drop_bounds)]
+            impl<T: ::core::ops::Drop> TracedTypesMustNotImplementDropUseFinalizeInstead for T {}
+            impl #impl_generics TracedTypesMustNotImplementDropUseFinalizeInstead for #name #ty_generics #where_clause {}
+        };
+    }
+}
+
+fn trace_derive(mut s: Structure) -> TokenStream {
+    s.underscore_const(true);
+
+    let options = match container_options(&s.ast().attrs) {
+        Ok(options) => options,
+        Err(err) => return err.to_compile_error(),
+    };
+    let drop_guard = if options.unsafe_drop { quote!() } else { forbid_drop(&s) };
+    let finalize = if options.finalize {
+        quote! {
+            #[inline]
+            fn finalize_glue(&self) {
+                ::gc_rs::Finalize::finalize(self);
+            }
+
+            #[inline]
+            fn finalizes() -> bool
+            where
+                Self: Sized,
+            {
+                true
+            }
+        }
+    } else {
+        quote!()
+    };
+
+    // Skipped fields aren't bound at all, so they needn't be `Trace`. Errors
+    // are reported from the match arm below.
+    s.filter(|bi| !matches!(field_mode(bi.ast()), Ok(FieldMode::Skip)));
+
+    // Like synstructure's bounds, but only for fields traced with `Trace`
+    let predicates = options.bound.unwrap_or_else(|| {
+        let traced = |bi: &&BindingInfo| matches!(field_mode(bi.ast()), Ok(FieldMode::Trace));
+        let bindings = s.variants().iter().flat_map(|v| v.bindings()).filter(traced);
+        let mut predicates: Vec<WherePredicate> = Vec::new();
+        for bi in bindings.filter(|bi| !bi.referenced_ty_params().is_empty()) {
+            let ty = &bi.ast().ty;
+            predicates.push(parse_quote!(#ty: ::gc_rs::Trace));
+            for param in bi.referenced_ty_params() {
+                predicates.push(parse_quote!(#param: ::gc_rs::Trace));
+            }
+        }
+        predicates
     });
-    let deroot_body = s.each(|bi| quote! {
-        ::gc_rs::Trace::deroot(#bi);
-        ::gc_rs::Trace::deroot_children(#bi);
+    s.add_bounds(AddBounds::None);
+    for predicate in predicates {
+        s.add_where_predicate(predicate);
+    }
+
+    let trace_body = s.each(|bi| match field_mode(bi.ast()) {
+        // Spanned as the user's code, so that the lint isn't silenced as
+        // coming from a macro, and points at the path
+        Ok(FieldMode::TraceWith(path)) => quote_spanned! {path.span()=>
+            // A safe fn here would be unsafe code that doesn't say so
+            #[deny(unused_unsafe)]
+            unsafe { #path(#bi, __gc_tracer) };
+        },
+        Ok(_) => quote! {
+            ::gc_rs::Trace::trace(#bi, __gc_tracer);
+        },
+        Err(err) => err.to_compile_error(),
     });
 
-    s.bound_impl(quote!(::gc_rs::Trace), quote! {
+    // Sound because every field is traced, other than those the attributes
+    // vouch for
+    let trace = s.unsafe_bound_impl(quote!(::gc_rs::Trace), quote! {
         #[inline]
-        fn trace(&self) {
+        fn trace(&self, __gc_tracer: &mut dyn ::gc_rs::Tracer) {
             match self { #trace_body }
         }
-        #[inline]
-        fn root_children(&self) {
-            match self { #root_body }
-        }
-        #[inline]
-        fn deroot_children(&self) {
-            match self { #deroot_body }
+
+        #finalize
+    });
+    quote! {
+        #trace
+        #drop_guard
+    }
+}
+
+// Implements `NoGc` (through `NoGcImpl`), and `Trace` as doing nothing,
+// requiring every field to be `NoGc` so there's no handle for it to miss
+fn no_gc_derive(mut s: Structure) -> TokenStream {
+    s.underscore_const(true);
+
+    let field_types: Vec<syn::Type> =
+        s.variants().iter().flat_map(|v| v.bindings()).map(|bi| bi.ast().ty.clone()).collect();
+    for ty in field_types {
+        s.add_where_predicate(parse_quote!(#ty: ::gc_rs::NoGc));
+    }
+
+    s.gen_impl(quote! {
+        gen unsafe impl ::gc_rs::NoGcImpl for @Self {}
+
+        gen unsafe impl ::gc_rs::Trace for @Self {
+            #[inline]
+            fn trace(&self, _: &mut dyn ::gc_rs::Tracer) {}
         }
-        #[inline]
-        fn root(&self) {}
-        #[inline]
-        fn deroot(&self) {}
     })
 }
 
-decl_derive!([Trace] => trace_derive);
+decl_derive!([Trace, attributes(gc)] => trace_derive);
+decl_derive!([NoGc] => no_gc_derive);
@@ -1,5 +1,4 @@
 use gc_rs_tests::*;
-use gc_rs::{Trace, Gc, GC_STATE};
 
 fn main() {
     manual_trait();
@@ -1,10 +1,12 @@
 #![feature(test)]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
 extern crate test;
 
-use gc_rs::{Trace, Gc, GC_STATE};
+use gc_rs::{stats, GcStats, Trace, Tracer, Gc, GC_STATE};
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod tests {
     use crate::*;
     use test::Bencher;
@@ -54,17 +56,45 @@ mod tests {
                 }
             }
             GC_STATE.with(|st| st.borrow_mut().collect_garbage());
-            let mut len: usize = 0;
-            let mut roots: usize = 0;
-            GC_STATE.with(|st| {
-                len = unsafe { st.borrow().get_ptrs_len() };
-                roots = unsafe { st.borrow().get_roots_len() };
-            });
+            let GcStats { live_objects: len, roots, .. } = stats();
             assert!(len == 0);
             assert!(roots == 0);
         });
     }
 
+    #[bench]
+    fn bench_long_lived_heap(b: &mut Bencher) {
+        #[derive(Trace)]
+        struct Foo {
+            pub x: i32,
+            pub y: String,
+        }
+
+        #[derive(Trace)]
+        struct Bar {
+            pub x: i32,
+            pub y: Gc<Foo>,
+        }
+
+        // Everything survives, so each collection is all marking and no freeing
+        let v: Vec<Gc<Bar>> = (0..100000)
+            .map(|i| {
+                Gc::new(Bar {
+                    x: i,
+                    y: Gc::new(Foo { x: i, y: "hello".to_string() }),
+                })
+            })
+            .collect();
+
+        b.iter(|| {
+            GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+        });
+
+        assert!(v.iter().enumerate().all(|(i, bar)| bar.y.x == i as i32));
+        drop(v);
+        GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    }
+
     #[test]
     fn test_hashmap() {
         hashmap();
@@ -74,35 +104,159 @@ mod tests {
     fn test_linked_list() {
         linked_list();
     }
+
+    #[test]
+    fn test_heap_stats() {
+        heap_stats();
+    }
+
+    #[test]
+    fn test_gc_hooks() {
+        gc_hooks();
+    }
+
+    #[test]
+    fn test_gc_start_hook_allocation() {
+        gc_start_hook_allocation();
+    }
+
+    #[test]
+    fn test_gc_hook_panic() {
+        gc_hook_panic();
+    }
+
+    #[test]
+    fn test_heap_snapshot() {
+        heap_snapshot();
+    }
+
+    #[test]
+    fn test_dot_dump() {
+        dot_dump();
+    }
+
+    #[test]
+    fn test_heap_census() {
+        heap_census();
+    }
+
+    #[test]
+    fn test_retainer_path() {
+        retainer_path();
+    }
+
+    #[test]
+    fn test_heap_verify() {
+        heap_verify();
+    }
+
+    #[test]
+    fn test_alloc_sites() {
+        alloc_sites();
+    }
+
+    #[test]
+    fn test_sampling_profiler() {
+        sampling_profiler();
+    }
+
+    #[test]
+    fn test_gc_log() {
+        gc_log();
+    }
+
+    #[test]
+    fn test_trace_events() {
+        trace_events();
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        snapshot_diff();
+    }
+
+    #[test]
+    fn test_dominator_tree() {
+        dominator_tree();
+    }
+
+    #[test]
+    fn test_snapshot_queries() {
+        snapshot_queries();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_inspection_server() {
+        inspection_server();
+    }
+
+    #[test]
+    fn test_custom_tracer() {
+        custom_tracer();
+    }
+
+    #[test]
+    fn test_no_gc_derive() {
+        no_gc_derive();
+    }
+
+    #[test]
+    fn test_derive_attributes() {
+        derive_attributes();
+    }
+
+    #[test]
+    fn test_finalize() {
+        finalize();
+    }
+
+    #[test]
+    fn test_finalize_unborrowed() {
+        finalize_unborrowed();
+    }
+
+    #[test]
+    fn test_result_and_map_keys() {
+        result_and_map_keys();
+    }
+
+    #[test]
+    fn test_std_collections() {
+        std_collections();
+    }
+
+    #[test]
+    fn test_smart_pointers() {
+        smart_pointers();
+    }
+
+    #[test]
+    fn test_dyn_trait() {
+        dyn_trait();
+    }
+
+    #[test]
+    fn test_downcast() {
+        downcast();
+    }
 }
 
 pub fn manual_trait() {
+    #[allow(dead_code)]
     struct Foo {
         pub x: i32,
         pub y: String,
     }
 
-    impl Trace for Foo {
-        fn trace(&self) {}
-
-        fn root_children(&self) {}
-
-        fn deroot_children(&self) {}
-
-        fn root(&self) {}
-
-        fn deroot(&self) {}
+    unsafe impl Trace for Foo {
+        fn trace(&self, _tracer: &mut dyn Tracer) {}
     }
 
     {
         let first = Gc::new(Foo { x: 1, y: "hi".to_string(), });
         GC_STATE.with(|st| st.borrow_mut().collect_garbage());
-        let mut len: usize = 0;
-        let mut roots: usize = 0;
-        GC_STATE.with(|st| {
-            len = unsafe { st.borrow().get_ptrs_len() };
-            roots = unsafe { st.borrow().get_roots_len() };
-        });
+        let GcStats { live_objects: len, roots, .. } = stats();
         assert!(first.is_root());
         assert!(len == 1);
         assert!(roots == 1);
@@ -110,54 +264,32 @@ pub fn manual_trait() {
 
     GC_STATE.with(|st| st.borrow_mut().collect_garbage());
 
-    assert!(GC_STATE.with(|st| {
-        let len = unsafe { st.borrow().get_ptrs_len() };
-        len
-    }) == 0);
+    assert!(stats().live_objects == 0);
 
+    #[allow(dead_code)]
     struct Bar {
         pub x: i32,
         pub y: Gc<Foo>,
     }
 
-    impl Trace for Bar {
-        fn trace(&self) {
-            self.y.trace();
-        }
-
-        fn root_children(&self) {
-            self.y.root();
-        }
-
-        fn deroot_children(&self) {
-            self.y.deroot();
+    unsafe impl Trace for Bar {
+        fn trace(&self, tracer: &mut dyn Tracer) {
+            self.y.trace(tracer);
         }
-
-        fn root(&self) {}
-
-        fn deroot(&self) {}
     }
 
     {
         let first = Gc::new(Foo { x: 1, y: "hi".to_string(), });
         let second = Gc::new(Bar { x: 2, y: first });
         assert!(!second.y.is_root());
-        let mut len: usize = 0;
-        let mut roots: usize = 0;
-        GC_STATE.with(|st| {
-            len = unsafe { st.borrow().get_ptrs_len() };
-            roots = unsafe { st.borrow().get_roots_len() };
-        });
+        let GcStats { live_objects: len, roots, .. } = stats();
         assert!(len == 2);
         assert!(roots == 1);
     }
 
     GC_STATE.with(|st| st.borrow_mut().collect_garbage());
 
-    assert!(GC_STATE.with(|st| {
-        let len = unsafe { st.borrow().get_ptrs_len() };
-        len
-    }) == 0);
+    assert!(stats().live_objects == 0);
 
     // test spammy allocation
     for _ in 0..1000 {
@@ -165,10 +297,7 @@ pub fn manual_trait() {
     }
 
     GC_STATE.with(|st| st.borrow_mut().collect_garbage());
-    assert!(GC_STATE.with(|st| {
-        let len = unsafe { st.borrow().get_ptrs_len() };
-        len
-    }) == 0);
+    assert!(stats().live_objects == 0);
 }
 
 pub fn auto_trait() {
@@ -181,12 +310,7 @@ pub fn auto_trait() {
     {
         let first = Gc::new(Foo { x: 1, y: "hi".to_string(), });
         GC_STATE.with(|st| st.borrow_mut().collect_garbage());
-        let mut len: usize = 0;
-        let mut roots: usize = 0;
-        GC_STATE.with(|st| {
-            len = unsafe { st.borrow().get_ptrs_len() };
-            roots = unsafe { st.borrow().get_roots_len() };
-        });
+        let GcStats { live_objects: len, roots, .. } = stats();
         assert!(first.is_root());
         assert!(len == 1);
         assert!(roots == 1);
@@ -194,10 +318,7 @@ pub fn auto_trait() {
 
     GC_STATE.with(|st| st.borrow_mut().collect_garbage());
 
-    assert!(GC_STATE.with(|st| {
-        let len = unsafe { st.borrow().get_ptrs_len() };
-        len
-    }) == 0);
+    assert!(stats().live_objects == 0);
 
     #[derive(Trace)]
     struct Bar {
@@ -209,22 +330,14 @@ pub fn auto_trait() {
         let first = Gc::new(Foo { x: 1, y: "hi".to_string(), });
         let second = Gc::new(Bar { x: 2, y: first });
         assert!(!second.y.is_root());
-        let mut len: usize = 0;
-        let mut roots: usize = 0;
-        GC_STATE.with(|st| {
-            len = unsafe { st.borrow().get_ptrs_len() };
-            roots = unsafe { st.borrow().get_roots_len() };
-        });
+        let GcStats { live_objects: len, roots, .. } = stats();
         assert!(len == 2);
         assert!(roots == 1);
     }
 
     GC_STATE.with(|st| st.borrow_mut().collect_garbage());
 
-    assert!(GC_STATE.with(|st| {
-        let len = unsafe { st.borrow().get_ptrs_len() };
-        len
-    }) == 0);
+    assert!(stats().live_objects == 0);
 
     // test spammy allocation
     for _ in 0..1000 {
@@ -232,12 +345,10 @@ pub fn auto_trait() {
     }
 
     GC_STATE.with(|st| st.borrow_mut().collect_garbage());
-    assert!(GC_STATE.with(|st| {
-        let len = unsafe { st.borrow().get_ptrs_len() };
-        len
-    }) == 0);
+    assert!(stats().live_objects == 0);
 }
 
+#[allow(clippy::vec_init_then_push)]
 pub fn vec() {
     #[derive(Trace)]
     struct Foo {
@@ -320,7 +431,1609 @@ pub fn linked_list() {
 
     assert!(from_vec(vec![1, 2, 3]) == reverse(from_vec(vec![3, 2, 1])));
 
-    assert!(from_vec(vec![]) == None);
+    assert!(from_vec(vec![]).is_none());
+
+    assert!(reverse(from_vec(vec![])).is_none());
+}
+
+pub fn heap_stats() {
+    #[derive(Trace)]
+    struct Foo {
+        pub x: i32,
+        pub y: String,
+    }
+
+    #[derive(Trace)]
+    struct Bar {
+        pub x: i32,
+        pub y: Gc<Foo>,
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    let before = stats();
+    assert!(before.live_objects == 0);
+    assert!(before.live_bytes == 0);
+    assert!(before.roots == 0);
+
+    {
+        let bar = Gc::new(Bar { x: 1, y: Gc::new(Foo { x: 2, y: "hi".to_string() }) });
+        let bar2 = bar.clone();
+        let after = stats();
+        assert!(after.live_objects == 2);
+        assert!(after.live_bytes > 0);
+        // Two handles on bar, but it's still only one rooted object
+        assert!(after.roots == 1);
+        assert!(bar2.y.x == 2);
+    }
+
+    assert!(stats().roots == 0);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let after = stats();
+    assert!(after.live_objects == 0);
+    assert!(after.live_bytes == 0);
+    assert!(after.collections == before.collections + 1);
+    assert!(after.objects_freed == before.objects_freed + 2);
+    assert!(after.bytes_freed > before.bytes_freed);
+    assert!(after.max_pause >= after.last_pause);
+    assert!(after.total_gc_time >= after.last_pause);
+}
+
+pub fn gc_hooks() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Trace)]
+    struct Foo {
+        pub x: i32,
+    }
+
+    let events = Rc::new(RefCell::new(Vec::new()));
+
+    let log = events.clone();
+    gc_rs::on_gc_start(move |before| {
+        // Allocating and reading stats from a hook must not trip over GC_STATE
+        let obj = Gc::new(Foo { x: 1 });
+        assert!(stats().live_objects == before.live_objects + 1);
+        log.borrow_mut().push(format!("start {}", obj.x));
+    });
+
+    let log = events.clone();
+    gc_rs::on_mark_done(move || {
+        let obj = Gc::new(Foo { x: 2 });
+        log.borrow_mut().push(format!("mark {}", obj.x));
+    });
+
+    let log = events.clone();
+    gc_rs::on_gc_end(move |delta| {
+        log.borrow_mut().push(format!("end {} {}", delta.collections, delta.objects_freed));
+    });
+
+    {
+        let _garbage = Gc::new(Foo { x: 0 });
+    }
+    gc_rs::collect_garbage();
+
+    // The object from on_gc_start is allocated before marking, so it's
+    // freed along with the garbage. The one from on_mark_done is allocated
+    // after, so it survives and is only freed by the next collection.
+    assert!(*events.borrow() == ["start 1", "mark 2", "end 1 2"]);
+    assert!(stats().live_objects == 1);
+
+    gc_rs::collect_garbage();
+    assert!(events.borrow()[3..] == ["start 1", "mark 2", "end 1 2"]);
+
+    let fired = Rc::new(RefCell::new(0));
+    let count = fired.clone();
+    let node_size = stats().live_bytes / stats().live_objects;
+    gc_rs::on_alloc_threshold(node_size * 10, move |stats| {
+        *count.borrow_mut() += 1;
+        assert!(stats.bytes_allocated > 0);
+        let _ = Gc::new(Foo { x: 3 });
+    });
+
+    for i in 0..100 {
+        let _ = Gc::new(Foo { x: i });
+    }
+    // 100 allocations plus the ones from the hook itself
+    assert!(*fired.borrow() >= 10);
+}
+
+pub fn gc_start_hook_allocation() {
+    use std::cell::RefCell;
+
+    #[derive(Trace)]
+    struct Holder {
+        pub x: Gc<Vec<i32>>,
+    }
+
+    #[derive(Trace)]
+    struct Wrapper {
+        pub h: Gc<Holder>,
+    }
+
+    thread_local! {
+        static KEPT: RefCell<Option<Gc<Wrapper>>> = const { RefCell::new(None) };
+    }
+
+    let mut x = Some(Gc::new(vec![1, 2, 3]));
+    gc_rs::on_gc_start(move |_| {
+        if let Some(x) = x.take() {
+            let w = Gc::new(Wrapper { h: Gc::new(Holder { x: x.clone() }) });
+            drop(x);
+            KEPT.with(|k| *k.borrow_mut() = Some(w));
+        }
+    });
+
+    // `x` is now only reachable through `w`, which the hook allocated
+    // before marking, so marking has to trace it like anything else
+    gc_rs::collect_garbage();
+    KEPT.with(|k| {
+        let w = k.borrow_mut().take().unwrap();
+        assert!(*w.h.x == vec![1, 2, 3]);
+    });
+    assert!(stats().live_objects == 3);
+}
+
+pub fn gc_hook_panic() {
+    use std::cell::Cell;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::rc::Rc;
+
+    #[derive(Trace)]
+    struct Foo {
+        pub x: i32,
+    }
+
+    let panics = Rc::new(Cell::new(1));
+    let left = panics.clone();
+    gc_rs::on_mark_done(move || {
+        if left.get() > 0 {
+            left.set(left.get() - 1);
+            panic!("hook failed");
+        }
+    });
+
+    let fired = Rc::new(Cell::new(0));
+    let count = fired.clone();
+    gc_rs::on_mark_done(move || count.set(count.get() + 1));
+
+    let kept = Gc::new(Foo { x: 1 });
+    drop(Gc::new(Foo { x: 2 }));
+    assert!(catch_unwind(AssertUnwindSafe(gc_rs::collect_garbage)).is_err());
+    assert!(panics.get() == 0);
+    assert!(fired.get() == 0);
+    assert!(stats().live_objects == 2);
+
+    // The abandoned collection mustn't block the next one, or leave marks
+    // behind that would stop it tracing. Neither hook is unregistered by
+    // the panic, so the second one runs this time.
+    gc_rs::collect_garbage();
+    assert!(fired.get() == 1);
+    assert!(stats().live_objects == 1);
+    assert!(kept.x == 1);
+    drop(kept);
+    gc_rs::collect_garbage();
+    assert!(stats().live_objects == 0);
+}
+
+pub fn heap_snapshot() {
+    #[derive(Trace)]
+    struct Leaf {
+        pub x: i32,
+    }
+
+    #[derive(Trace)]
+    struct Pair {
+        pub left: Gc<Leaf>,
+        pub right: Gc<Leaf>,
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let pair = Gc::new(Pair {
+        left: Gc::new(Leaf { x: 1 }),
+        right: Gc::new(Leaf { x: 2 }),
+    });
+
+    let mut out = Vec::new();
+    GC_STATE.with(|st| st.borrow().write_heap_snapshot(&mut out)).unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    // The synthetic root, then the three objects. One edge from the root
+    // to the pair, then one to each leaf.
+    assert!(out.contains(r#""node_count":4,"edge_count":3"#));
+    assert!(out.contains(r#""(GC roots)""#));
+    assert!(out.contains("heap_snapshot::Pair"));
+    assert!(out.contains("heap_snapshot::Leaf"));
+
+    // Objects are identified by the same ids as in `gc_rs::snapshot`, and
+    // the root by 0
+    let start = out.find("\"nodes\":[").unwrap() + "\"nodes\":[".len();
+    let end = start + out[start..].find(']').unwrap();
+    let fields: Vec<u64> = out[start..end].split(',').map(|f| f.parse().unwrap()).collect();
+    let mut ids: Vec<u64> = fields.chunks(6).map(|node| node[2]).collect();
+    assert!(ids.remove(0) == 0);
+    ids.sort();
+    let mut expected: Vec<u64> = gc_rs::snapshot().objects.iter().map(|o| o.id).collect();
+    expected.sort();
+    assert!(ids == expected);
+
+    let path = std::env::temp_dir().join(format!("gc_rs_{}.heapsnapshot", std::process::id()));
+    gc_rs::write_heap_snapshot(&path).unwrap();
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(written == out);
+    assert!(pair.left.x + pair.right.x == 3);
+}
+
+pub fn dot_dump() {
+    use gc_rs::DotOptions;
+
+    #[derive(Trace)]
+    struct Node {
+        pub next: Option<Gc<Node>>,
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let mut head = None;
+    for _ in 0..4 {
+        head = Some(Gc::new(Node { next: head }));
+    }
+    let head = head.unwrap();
+    let third = head.next.as_ref().unwrap().next.clone().unwrap();
+
+    fn dump(options: &DotOptions) -> (usize, usize) {
+        let mut out = Vec::new();
+        gc_rs::dump_dot(&mut out, options).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("digraph heap {"));
+        let nodes = out.lines().filter(|l| l.contains("[label=")).count();
+        let edges = out.lines().filter(|l| l.contains("->")).count();
+        (nodes, edges)
+    }
+
+    assert!(dump(&DotOptions::new()) == (4, 3));
+    assert!(dump(&DotOptions::new().reachable_from(&third)) == (2, 1));
+    drop(third);
+    // Only the head is rooted now
+    assert!(dump(&DotOptions::new().max_depth(1)) == (2, 1));
+    assert!(dump(&DotOptions::new().reachable_from(&head).max_depth(0)) == (1, 0));
+
+    let mut out = Vec::new();
+    GC_STATE.with(|st| st.borrow().dump_dot(&mut out)).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("dot_dump::Node\\n"));
+    assert!(out.contains("\\nroots: 1\", style=bold"));
+}
+
+pub fn heap_census() {
+    use gc_rs::census;
+
+    #[derive(Trace)]
+    struct Leaf {
+        pub x: i32,
+    }
+
+    #[derive(Trace)]
+    struct Pair {
+        pub left: Gc<Leaf>,
+        pub right: Gc<Leaf>,
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(census().is_empty());
+
+    let pair = Gc::new(Pair {
+        left: Gc::new(Leaf { x: 1 }),
+        right: Gc::new(Leaf { x: 2 }),
+    });
+    {
+        let _garbage = Gc::new(Leaf { x: 3 });
+    }
+
+    let entries = census();
+    assert!(entries == GC_STATE.with(|st| st.borrow().walk_census()));
+    assert!(entries.len() == 2);
+    let leaves = entries.iter().find(|e| e.type_name.ends_with("heap_census::Leaf")).unwrap();
+    let pairs = entries.iter().find(|e| e.type_name.ends_with("heap_census::Pair")).unwrap();
+    assert!(leaves.count == 3);
+    assert!(pairs.count == 1);
+    assert!(leaves.bytes + pairs.bytes == stats().live_bytes);
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    let entries = census();
+    assert!(entries == GC_STATE.with(|st| st.borrow().walk_census()));
+    let leaves = entries.iter().find(|e| e.type_name.ends_with("heap_census::Leaf")).unwrap();
+    assert!(leaves.count == 2);
+
+    drop(pair);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(census().is_empty());
+}
+
+pub fn retainer_path() {
+    use gc_rs::retaining_path;
+
+    #[derive(Trace)]
+    struct Leaf {
+        pub x: i32,
+    }
+
+    #[derive(Trace)]
+    struct Mid {
+        pub leaf: Gc<Leaf>,
+    }
+
+    #[derive(Trace)]
+    struct Holder {
+        pub first: Gc<Mid>,
+        pub second: Gc<Mid>,
+    }
+
+    let holder = Gc::new(Holder {
+        first: Gc::new(Mid { leaf: Gc::new(Leaf { x: 1 }) }),
+        second: Gc::new(Mid { leaf: Gc::new(Leaf { x: 2 }) }),
+    });
+
+    // Unrooted handle inside the heap
+    let path = retaining_path(&holder.second.leaf).unwrap();
+    assert!(path.len() == 3, "{:?}", path);
+    assert!(path.root().type_name.ends_with("retainer_path::Holder"));
+    assert!(path.steps[1].type_name.ends_with("retainer_path::Mid"));
+    assert!(path.steps[2].type_name.ends_with("retainer_path::Leaf"));
+
+    // A rooted handle doesn't count as its own retainer
+    let leaf = holder.first.leaf.clone();
+    assert!(leaf.is_root());
+    let path = retaining_path(&leaf).unwrap();
+    assert!(path.len() == 3, "{:?}", path);
+    let debug = format!("{:?}", path);
+    assert!(debug.starts_with("(root) "));
+    assert!(debug.matches("->").count() == 2);
 
-    assert!(reverse(from_vec(vec![])) == None);
+    // A second handle does
+    let leaf2 = leaf.clone();
+    assert!(retaining_path(&leaf).unwrap().len() == 1);
+    drop(leaf2);
+
+    drop(holder);
+    assert!(retaining_path(&leaf).is_none());
+    assert!(leaf.x == 1);
 }
+
+pub fn heap_verify() {
+    use gc_rs::{leak_warnings, set_verify_after_gc, verify_heap, Violation};
+    use std::cell::Cell;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    #[derive(Trace)]
+    struct Foo {
+        pub x: i32,
+    }
+
+    #[derive(Trace)]
+    struct Bar {
+        pub y: Gc<Foo>,
+    }
+
+    set_verify_after_gc(true);
+    {
+        let bar = Gc::new(Bar { y: Gc::new(Foo { x: 1 }) });
+        let _garbage = Gc::new(Foo { x: 2 });
+        assert!(verify_heap().is_ok());
+        GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+        assert!(bar.y.x == 1);
+    }
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(verify_heap().is_ok());
+
+    // Storing a handle through `borrow_mut` without derooting it leaks the
+    // target, but leaves the heap sound
+    let forgetful = Gc::new(Bar { y: Gc::new(Foo { x: 0 }) });
+    forgetful.borrow_mut().unwrap().y = Gc::new(Foo { x: 3 });
+    assert!(verify_heap().is_ok());
+    let warnings = leak_warnings();
+    assert!(warnings.len() == 1);
+    assert!(warnings[0].holder_type.ends_with("Bar") && warnings[0].target_type.ends_with("Foo"));
+    assert!(warnings[0].to_string().contains("heap_verify::Bar"));
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(forgetful.y.x == 3);
+
+    // Misses its handle when marking (the second trace, after the one
+    // that deroots it), so the target is freed from under it
+    struct Flaky {
+        pub y: Gc<Foo>,
+        pub traced: Cell<usize>,
+    }
+
+    unsafe impl Trace for Flaky {
+        fn trace(&self, tracer: &mut dyn Tracer) {
+            self.traced.set(self.traced.get() + 1);
+            if self.traced.get() != 2 {
+                self.y.trace(tracer);
+            }
+        }
+    }
+
+    let _flaky = Gc::new(Flaky { y: Gc::new(Foo { x: 4 }), traced: Cell::new(0) });
+    let collected = catch_unwind(AssertUnwindSafe(|| {
+        GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    }));
+    set_verify_after_gc(false);
+    let message = collected.unwrap_err().downcast::<String>().unwrap();
+    assert!(message.contains("heap_verify::Flaky"));
+    assert!(message.contains("isn't on the heap"));
+    let err = verify_heap().unwrap_err();
+    assert!(err.violations.len() == 1);
+    assert!(matches!(err.violations[0], Violation::DanglingEdge { holder_type, .. } if holder_type.ends_with("Flaky")));
+}
+
+pub fn alloc_sites() {
+    use gc_rs::{census_by_site, retaining_path};
+
+    #[derive(Trace)]
+    struct Leaf {
+        pub x: i32,
+    }
+
+    #[derive(Trace)]
+    struct Holder {
+        pub leaf: Gc<Leaf>,
+    }
+
+    fn make_leaf(x: i32) -> Gc<Leaf> {
+        Gc::new(Leaf { x })
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let (line, holder) = (line!(), Gc::new(Holder { leaf: make_leaf(1) }));
+    let others: Vec<_> = (0..3).map(make_leaf).collect();
+
+    let entries = census_by_site();
+    assert!(entries.len() == 2);
+    let holders = entries.iter().find(|e| e.type_name.ends_with("Holder")).unwrap();
+    let site = holders.alloc_site.unwrap();
+    assert!(site.file().ends_with("lib.rs"));
+    assert!(site.line() == line);
+    // All the leaves come from the same line in make_leaf
+    let leaves = entries.iter().find(|e| e.type_name.ends_with("Leaf")).unwrap();
+    assert!(leaves.count == 4);
+
+    let path = retaining_path(&holder.leaf).unwrap();
+    assert!(path.root().alloc_site == Some(site));
+    assert!(format!("{:?}", path).contains(&format!("allocated at {}", site)));
+
+    let mut out = Vec::new();
+    GC_STATE.with(|st| st.borrow().write_heap_snapshot(&mut out)).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains(&format!("alloc_sites::Holder @ {}", site)));
+
+    assert!(others.iter().map(|l| l.x).sum::<i32>() == 3);
+}
+
+pub fn sampling_profiler() {
+    use gc_rs::{set_sample_interval, write_collapsed_stacks};
+
+    #[derive(Trace)]
+    struct Sampled {
+        pub x: i32,
+    }
+
+    #[inline(never)]
+    fn sampled_alloc_site(x: i32) -> Gc<Sampled> {
+        Gc::new(Sampled { x })
+    }
+
+    fn report() -> String {
+        let mut out = Vec::new();
+        write_collapsed_stacks(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    // Sample every allocation
+    set_sample_interval(Some(1));
+    let kept: Vec<_> = (0..10).map(sampled_alloc_site).collect();
+    {
+        let _garbage = sampled_alloc_site(10);
+    }
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let out = report();
+    let line = out.lines().find(|l| l.contains("sampled_alloc_site")).unwrap();
+    let (stack, bytes) = line.rsplit_once(' ').unwrap();
+    assert!(!stack.split(';').any(|f| f.trim_start_matches('<').starts_with("gc_rs::")));
+    assert!(stack.ends_with("sampled_alloc_site"), "{}", stack);
+    assert!(bytes.parse::<usize>().unwrap() == stats().live_bytes);
+
+    drop(kept);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(report().is_empty());
+
+    // Only about one in ten, each standing in for the ten allocations
+    // since the last
+    let node_size = stats().bytes_freed / stats().objects_freed;
+    set_sample_interval(Some(node_size * 10));
+    let kept: Vec<_> = (0..100).map(sampled_alloc_site).collect();
+    let sampled: usize = report().lines().map(|l| l.rsplit_once(' ').unwrap().1.parse::<usize>().unwrap()).sum();
+    assert!(sampled == node_size * 100);
+
+    set_sample_interval(None);
+    assert!(report().is_empty());
+    assert!(kept.len() == 100);
+}
+
+pub fn gc_log() {
+    use gc_rs::{collect_garbage, last_collection, set_gc_log_level, GcLogLevel, GcReason};
+
+    #[derive(Trace)]
+    struct Logged {
+        pub x: i32,
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    set_gc_log_level(GcLogLevel::Census);
+
+    let kept = Gc::new(Logged { x: 1 });
+    for x in 0..5 {
+        let _garbage = Gc::new(Logged { x });
+    }
+    let before = stats();
+    collect_garbage();
+
+    let record = last_collection().unwrap();
+    assert!(record.reason == GcReason::Explicit);
+    assert!(record.number == stats().collections);
+    assert!(record.before.live_objects == before.live_objects);
+    assert!(record.after.live_objects == before.live_objects - 5);
+    assert!(record.after.live_bytes < record.before.live_bytes);
+    assert!(record.roots_scanned == stats().roots);
+
+    let line = record.to_string();
+    assert!(line.starts_with(&format!("[gc_rs] gc #{} (explicit): ", record.number)), "{}", line);
+    assert!(line.contains(&format!("{} -> {} objects", record.before.live_objects, record.after.live_objects)));
+    assert!(!line.contains('\n'));
+
+    set_gc_log_level(GcLogLevel::Off);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(last_collection().unwrap().number == record.number + 1);
+    assert!(kept.x == 1);
+}
+
+pub fn trace_events() {
+    use gc_rs::{collect_garbage, flush_trace_events, set_trace_events, trace_clock_us};
+
+    #[derive(Trace)]
+    struct Traced {
+        pub x: i32,
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    set_trace_events(true);
+
+    let start = trace_clock_us();
+    let kept = Gc::new(Traced { x: 1 });
+    collect_garbage();
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let mut out = Vec::new();
+    GC_STATE.with(|st| st.borrow_mut().write_trace_events(&mut out)).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with(r#"{"traceEvents":["#));
+    assert!(out.contains(r#""ph":"M""#));
+    assert!(out.matches(r#""name":"gc","cat":"gc","ph":"X""#).count() == 2);
+    assert!(out.matches(r#""name":"mark""#).count() == 2);
+    assert!(out.matches(r#""name":"sweep""#).count() == 2);
+    assert!(out.contains(r#""reason":"explicit""#));
+
+    // Each phase falls inside its collection, after the clock was read
+    let field = |line: &str, name: &str| -> f64 {
+        let rest = &line[line.find(&format!("\"{}\":", name)).unwrap() + name.len() + 3..];
+        rest[..rest.find(',').unwrap()].parse().unwrap()
+    };
+    let gc = out.lines().find(|l| l.contains(r#""name":"gc""#)).unwrap();
+    let mark = out.lines().find(|l| l.contains(r#""name":"mark""#)).unwrap();
+    assert!(field(gc, "ts") >= start);
+    assert!(field(mark, "ts") >= field(gc, "ts"));
+    assert!(field(mark, "ts") + field(mark, "dur") <= field(gc, "ts") + field(gc, "dur"));
+
+    // Written events are forgotten
+    let path = std::env::temp_dir().join(format!("gc_rs_{}.trace.json", std::process::id()));
+    flush_trace_events(&path).unwrap();
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(!written.contains(r#""ph":"X""#));
+
+    set_trace_events(false);
+    collect_garbage();
+    let mut out = Vec::new();
+    GC_STATE.with(|st| st.borrow_mut().write_trace_events(&mut out)).unwrap();
+    assert!(out == b"{\"traceEvents\":[]}\n");
+    assert!(kept.x == 1);
+}
+
+pub fn snapshot_diff() {
+    use gc_rs::{snapshot, HeapSnapshot};
+
+    #[derive(Trace)]
+    struct Session {
+        pub id: usize,
+        pub prev: Option<Gc<Session>>,
+    }
+
+    #[derive(Trace)]
+    struct Request {
+        pub id: usize,
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let mut sessions = Gc::new(Session { id: 0, prev: None });
+    let before = snapshot();
+    assert!(before.objects.len() == 1);
+    let first_id = before.objects[0].id;
+
+    // Handling requests leaks a session each time
+    for id in 1..=10 {
+        {
+            let _request = Gc::new(Request { id });
+        }
+        let mut prev = Gc::new(Session { id, prev: None });
+        std::mem::swap(&mut prev, &mut sessions);
+        // SAFETY: it's moved into `sessions` straight after
+        unsafe { prev.deroot() };
+        sessions.borrow_mut().unwrap().prev = Some(prev);
+    }
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    let after = snapshot();
+
+    // The first session kept its id, and its holder points at it by id
+    let first = after.get(first_id).unwrap();
+    assert!(first.type_name.ends_with("snapshot_diff::Session"));
+    assert!(after.objects.iter().any(|o| o.edges == vec![first_id]));
+
+    let diff = before.diff(&after);
+    assert!(diff.objects_before == 1 && diff.objects_after == 11);
+    assert!(diff.new_objects.len() == 10 && diff.freed_objects.is_empty());
+    let grown: Vec<_> = diff.grown().collect();
+    assert!(grown.len() == 1);
+    assert!(grown[0].type_name.ends_with("snapshot_diff::Session"));
+    assert!(grown[0].count_delta() == 10);
+    assert!(grown[0].alloc_site.as_ref().unwrap().contains("gc_rs_tests/src/lib.rs"));
+
+    let report = diff.to_string();
+    assert!(report.starts_with("1 -> 11 objects (+10)"), "{}", report);
+    assert!(report.contains("grew:") && !report.contains("shrank:"));
+    assert!(!report.contains("Request"));
+
+    // Saving and loading gives the same snapshot
+    let path = std::env::temp_dir().join(format!("gc_rs_{}.snapshot.json", std::process::id()));
+    after.save(&path).unwrap();
+    let loaded = HeapSnapshot::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(loaded == after);
+    assert!(HeapSnapshot::read_json(&b"{\"objects\":[]}"[..]).is_err());
+
+    drop(sessions);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    let diff = after.diff(&snapshot());
+    assert!(diff.freed_objects.len() == 11);
+    assert!(diff.to_string().contains("shrank:"));
+}
+
+pub fn dominator_tree() {
+    use gc_rs::{snapshot, HeapSnapshot, SnapshotObject};
+
+    #[derive(Trace)]
+    struct Obj {
+        pub data: Vec<i32>,
+        pub left: Option<Gc<Obj>>,
+        pub right: Option<Gc<Obj>>,
+    }
+
+    fn obj(left: Option<Gc<Obj>>, right: Option<Gc<Obj>>) -> Gc<Obj> {
+        Gc::new(Obj { data: vec![], left, right })
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    // a -> b -> d -> e
+    //   \-> c -/
+    let e = obj(None, None);
+    let d = obj(Some(e.clone()), None);
+    let b = obj(Some(d.clone()), None);
+    let c = obj(Some(d.clone()), None);
+    let a = obj(Some(b.clone()), Some(c.clone()));
+    let ids: Vec<u64> = snapshot().objects.iter().map(|o| o.id).collect();
+    // Allocated e, d, b, c, a, and listed most recent first
+    let (a_id, c_id, b_id, d_id, e_id) = (ids[0], ids[1], ids[2], ids[3], ids[4]);
+    drop((b, c, d, e));
+
+    let snap = snapshot();
+    let tree = snap.dominators();
+    let size = snap.get(a_id).unwrap().size;
+    assert!(tree.immediate_dominator(a_id).is_none());
+    assert!(tree.immediate_dominator(b_id) == Some(a_id));
+    assert!(tree.immediate_dominator(c_id) == Some(a_id));
+    assert!(tree.immediate_dominator(d_id) == Some(a_id));
+    assert!(tree.immediate_dominator(e_id) == Some(d_id));
+    assert!(tree.retained_size(a_id) == Some(size * 5));
+    assert!(tree.retained_size(b_id) == Some(size));
+    assert!(tree.retained_size(d_id) == Some(size * 2));
+    assert!(tree.top_level() == vec![a_id]);
+    assert!(tree.top_objects(2) == vec![a_id, d_id]);
+
+    // Objects under one of the same type aren't counted twice
+    let types = tree.top_types(10);
+    assert!(types.len() == 1);
+    assert!(types[0].count == 5 && types[0].retained == size * 5);
+
+    // Anything held by two roots is only dominated by the roots
+    let other = obj(None, None);
+    a.borrow_mut().unwrap().right = None;
+    let x = obj(Some(other.clone()), None);
+    let y = obj(Some(other.clone()), None);
+    let other_id = snapshot().objects[2].id;
+    drop(other);
+    let snap = snapshot();
+    let tree = snap.dominators();
+    assert!(tree.immediate_dominator(other_id).is_none());
+    assert!(tree.top_level().contains(&other_id));
+    // c is garbage now, so has no place in the tree
+    assert!(tree.retained_size(c_id).is_none());
+
+    // Deep chains don't overflow the stack
+    let objects: Vec<SnapshotObject> = (1..=100_000u64)
+        .map(|id| SnapshotObject {
+            id,
+            type_name: "Link".to_string(),
+            alloc_site: None,
+            size: 8,
+            roots: (id == 1) as usize,
+            edges: if id < 100_000 { vec![id + 1] } else { vec![] },
+        })
+        .collect();
+    let chain = HeapSnapshot { objects };
+    let tree = chain.dominators();
+    assert!(tree.retained_size(1) == Some(800_000));
+    assert!(tree.immediate_dominator(100_000) == Some(99_999));
+    assert!(tree.top_types(1)[0].retained == 800_000);
+
+    assert!(x.left.is_some() && y.left.is_some() && a.data.is_empty());
+}
+
+pub fn snapshot_queries() {
+    use gc_rs::snapshot;
+
+    #[derive(Trace)]
+    struct Node {
+        pub next: Option<Gc<Node>>,
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let tail = Gc::new(Node { next: None });
+    let mid = Gc::new(Node { next: Some(tail) });
+    let head = Gc::new(Node { next: Some(mid) });
+    let stray = Gc::new(Node { next: None });
+    // SAFETY: it isn't used again, it's only there to be unreachable
+    unsafe { stray.deroot() };
+
+    let snap = snapshot();
+    let ids: Vec<u64> = snap.objects.iter().map(|o| o.id).collect();
+    let (stray_id, head_id, mid_id, tail_id) = (ids[0], ids[1], ids[2], ids[3]);
+
+    assert!(snap.referrers(tail_id) == vec![mid_id]);
+    assert!(snap.referrers(head_id).is_empty());
+    assert!(snap.path_to_root(tail_id) == Some(vec![head_id, mid_id, tail_id]));
+    assert!(snap.path_to_root(head_id) == Some(vec![head_id]));
+    assert!(snap.path_to_root(stray_id).is_none());
+
+    let mut out = Vec::new();
+    snap.dump_dot(&mut out, head_id, Some(1)).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains(&format!("\"{}\" -> \"{}\";", head_id, mid_id)));
+    assert!(out.contains(", style=bold"));
+    assert!(!out.contains(&format!("\"{}\"", tail_id)));
+    assert!(head.next.is_some());
+}
+
+#[cfg(unix)]
+pub fn inspection_server() {
+    use gc_rs::{safepoint, start_inspection_server, stop_inspection_server, HeapSnapshot};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::time::{Duration, Instant};
+
+    #[derive(Trace)]
+    struct Served {
+        pub x: i32,
+    }
+
+    fn ask(path: &std::path::Path, query: &str) -> std::thread::JoinHandle<String> {
+        let path = path.to_path_buf();
+        let query = query.to_string();
+        std::thread::spawn(move || {
+            let mut stream = UnixStream::connect(path).unwrap();
+            writeln!(stream, "{}", query).unwrap();
+            let mut answer = String::new();
+            stream.read_to_string(&mut answer).unwrap();
+            answer
+        })
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    let path = std::env::temp_dir().join(format!("gc_rs_{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    start_inspection_server(&path).unwrap();
+    assert!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777 == 0o600);
+    let kept = Gc::new(Served { x: 1 });
+
+    // Answered at a safepoint
+    let client = ask(&path, "stats");
+    while !client.is_finished() {
+        safepoint();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let answer = client.join().unwrap();
+    assert!(answer.contains("live_objects: 1,"), "{}", answer);
+
+    // Answered at an allocation
+    let client = ask(&path, "snapshot");
+    let mut garbage = Vec::new();
+    while !client.is_finished() {
+        garbage.push(Gc::new(Served { x: 2 }));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let snapshot = HeapSnapshot::read_json(client.join().unwrap().as_bytes()).unwrap();
+    assert!(snapshot.objects.iter().any(|o| o.type_name.ends_with("inspection_server::Served")));
+
+    let client = ask(&path, "census");
+    while !client.is_finished() {
+        safepoint();
+    }
+    assert!(client.join().unwrap().contains("inspection_server::Served"));
+
+    let client = ask(&path, "nonsense");
+    while !client.is_finished() {
+        safepoint();
+    }
+    assert!(client.join().unwrap().starts_with("error: unknown query"));
+
+    stop_inspection_server();
+    assert!(!path.exists());
+
+    // Stopping doesn't need the socket to still be there
+    start_inspection_server(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let start = Instant::now();
+    stop_inspection_server();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert!(kept.x == 1 && !garbage.is_empty());
+}
+
+pub fn custom_tracer() {
+    use gc_rs::{deroot_children, root_children, GcEdge};
+
+    #[derive(Trace)]
+    struct Leaf {
+        pub x: i32,
+    }
+
+    #[derive(Trace)]
+    enum Shape {
+        Empty,
+        One(Gc<Leaf>),
+        Many { leaves: Vec<Gc<Leaf>>, extra: Option<Gc<Leaf>> },
+    }
+
+    struct Counter {
+        edges: usize,
+        rooted: usize,
+    }
+
+    impl Tracer for Counter {
+        fn edge(&mut self, edge: GcEdge<'_>) {
+            self.edges += 1;
+            self.rooted += edge.is_root() as usize;
+        }
+    }
+
+    fn count(value: &impl Trace) -> (usize, usize) {
+        let mut counter = Counter { edges: 0, rooted: 0 };
+        value.trace(&mut counter);
+        (counter.edges, counter.rooted)
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let many = Shape::Many {
+        leaves: (0..3).map(|x| Gc::new(Leaf { x })).collect(),
+        extra: Some(Gc::new(Leaf { x: 3 })),
+    };
+    assert!(count(&Shape::Empty) == (0, 0));
+    assert!(count(&Shape::One(Gc::new(Leaf { x: 0 }))) == (1, 1));
+    assert!(count(&many) == (4, 4));
+
+    // SAFETY: rooted again before anything can collect
+    unsafe { deroot_children(&many) };
+    assert!(count(&many) == (4, 0));
+    root_children(&many);
+    assert!(count(&many) == (4, 4));
+
+    // Handles in a Vec are derooted when it goes into the heap, and only
+    // reachable through it
+    let shape = Gc::new(many);
+    assert!(count(&*shape) == (4, 0));
+    assert!(stats().roots == 1);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 5);
+    drop(shape);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 0);
+}
+
+pub fn no_gc_derive() {
+    use gc_rs::NoGc;
+
+    #[derive(NoGc, Debug, PartialEq)]
+    enum Unit {
+        Metres,
+        Feet,
+    }
+
+    #[derive(NoGc, Debug, PartialEq)]
+    struct Reading<T> {
+        value: T,
+        unit: Unit,
+        tags: Vec<String>,
+    }
+
+    #[derive(Trace)]
+    struct Sensor {
+        pub readings: Vec<Reading<f64>>,
+        pub previous: Option<Gc<Sensor>>,
+    }
+
+    fn takes_no_gc<T: NoGc>(_: &T) {}
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let reading = Reading { value: 1.5, unit: Unit::Metres, tags: vec!["a".to_string()] };
+    takes_no_gc(&reading);
+    takes_no_gc(&Some(vec![Reading { value: 1u8, unit: Unit::Feet, tags: vec![] }]));
+
+    let first = Gc::new(Sensor { readings: vec![reading], previous: None });
+    let second = Gc::new(Sensor { readings: vec![], previous: Some(first) });
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 2);
+    let previous = second.previous.as_ref().unwrap();
+    assert!(previous.readings[0] == Reading { value: 1.5, unit: Unit::Metres, tags: vec!["a".to_string()] });
+
+    drop(second);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 0);
+}
+
+pub fn derive_attributes() {
+    use std::marker::PhantomData;
+
+    // Stand-ins for types from other crates, without `Trace`
+    struct FileHandle {
+        pub fd: i32,
+    }
+
+    struct Registry {
+        pub entries: Vec<Gc<i32>>,
+    }
+
+    unsafe fn trace_registry(registry: &Registry, tracer: &mut dyn Tracer) {
+        registry.entries.trace(tracer);
+    }
+
+    #[derive(Trace)]
+    struct Session {
+        #[gc(skip)]
+        pub file: FileHandle,
+        #[gc(trace_with = "trace_registry")]
+        pub registry: Registry,
+        pub name: Gc<String>,
+    }
+
+    // `M` is only in a skipped field, so needn't be `Trace`
+    #[derive(Trace)]
+    struct Tagged<T: Trace + 'static, M> {
+        pub value: Gc<T>,
+        #[gc(skip)]
+        pub meta: M,
+    }
+
+    // Without the bound, the derive would ask for `PhantomData<K>: Trace`
+    #[derive(Trace)]
+    #[gc(bound = "T: Trace")]
+    struct Keyed<K, T: Trace + 'static> {
+        pub values: Vec<Gc<T>>,
+        #[gc(skip)]
+        pub key: PhantomData<K>,
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let session = Gc::new(Session {
+        file: FileHandle { fd: 3 },
+        registry: Registry { entries: (0..3).map(Gc::new).collect() },
+        name: Gc::new("user".to_string()),
+    });
+    assert!(session.registry.entries.iter().all(|entry| !entry.is_root()));
+    assert!(!session.name.is_root());
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 5);
+    assert!(session.file.fd == 3);
+    assert!(session.registry.entries.iter().map(|entry| **entry).sum::<i32>() == 3);
+    assert!(*session.name == "user");
+    drop(session);
+
+    let tagged = Gc::new(Tagged { value: Gc::new(1), meta: FileHandle { fd: 4 } });
+    let keyed = Gc::new(Keyed::<FileHandle, i32> { values: vec![Gc::new(2), Gc::new(3)], key: PhantomData });
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 5);
+    assert!(*tagged.value == 1 && tagged.meta.fd == 4);
+    assert!(keyed.values.iter().map(|value| **value).sum::<i32>() == 5);
+    drop(tagged);
+    drop(keyed);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 0);
+}
+
+pub fn finalize() {
+    use gc_rs::Finalize;
+    use std::cell::{Cell, RefCell};
+
+    thread_local! {
+        static FINALIZED: RefCell<Vec<i32>> = const { RefCell::new(Vec::new()) };
+        static KEEP: Cell<bool> = const { Cell::new(false) };
+        static KEPT: RefCell<Vec<Gc<Node>>> = const { RefCell::new(Vec::new()) };
+        static DROPPED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[derive(Trace)]
+    #[gc(finalize)]
+    struct Node {
+        pub x: i32,
+        pub next: Option<Gc<Node>>,
+    }
+
+    impl Finalize for Node {
+        fn finalize(&self) {
+            // Whatever's next is unreachable too, but not yet freed
+            let next = self.next.as_ref().map_or(-1, |next| next.x);
+            FINALIZED.with(|f| f.borrow_mut().push(self.x * 10 + next));
+            if KEEP.with(Cell::get) {
+                if let Some(next) = &self.next {
+                    KEPT.with(|k| k.borrow_mut().push(next.clone()));
+                }
+            }
+        }
+    }
+
+    #[derive(Trace)]
+    #[gc(unsafe_drop)]
+    struct Counted {
+        pub x: i32,
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPPED.with(|d| d.set(d.get() + self.x as usize));
+        }
+    }
+
+    fn finalized() -> Vec<i32> {
+        let mut f = FINALIZED.with(|f| std::mem::take(&mut *f.borrow_mut()));
+        f.sort();
+        f
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let head = Gc::new(Node { x: 1, next: Some(Gc::new(Node { x: 2, next: Some(Gc::new(Node { x: 3, next: None })) })) });
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(finalized().is_empty());
+    drop(head);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(finalized() == vec![12, 23, 29]);
+    assert!(stats().live_objects == 0);
+
+    // A finalizer that keeps a handle keeps what it reaches alive
+    KEEP.with(|k| k.set(true));
+    let head = Gc::new(Node { x: 4, next: Some(Gc::new(Node { x: 5, next: Some(Gc::new(Node { x: 6, next: None })) })) });
+    drop(head);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(finalized() == vec![45, 56, 59]);
+    assert!(stats().live_objects == 2);
+    KEEP.with(|k| k.set(false));
+    let kept = KEPT.with(|k| std::mem::take(&mut *k.borrow_mut()));
+    assert!(kept.iter().map(|node| node.x).collect::<Vec<_>>() == vec![5, 6]);
+    assert!(kept[0].next.as_ref().unwrap().x == 6);
+    drop(kept);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(finalized() == vec![56, 59]);
+    assert!(stats().live_objects == 0);
+
+    let counted = Gc::new(Counted { x: 7 });
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(DROPPED.with(Cell::get) == 0);
+    drop(counted);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(DROPPED.with(Cell::get) == 7);
+
+    // Whether an object finalizes is kept in its header, newest first
+    let node = Gc::new(Node { x: 8, next: None });
+    let counted = Gc::new(Counted { x: 0 });
+    GC_STATE.with(|st| {
+        let finalizes: Vec<bool> = st.borrow().nodes().map(|node| node.data.get().finalizes()).collect();
+        assert!(finalizes == [false, true]);
+    });
+    drop((node, counted));
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(finalized() == vec![79]);
+    assert!(stats().live_objects == 0);
+}
+
+pub fn finalize_unborrowed() {
+    use gc_rs::Finalize;
+    use std::cell::{Cell, RefCell};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    thread_local! {
+        static KEPT: RefCell<Vec<Gc<Holder>>> = const { RefCell::new(Vec::new()) };
+        static PANIC: Cell<bool> = const { Cell::new(false) };
+    }
+
+    #[derive(Trace)]
+    struct Holder {
+        pub x: Gc<Vec<i32>>,
+    }
+
+    #[derive(Trace)]
+    #[gc(finalize)]
+    struct Node {
+        pub x: Gc<Vec<i32>>,
+    }
+
+    impl Finalize for Node {
+        fn finalize(&self) {
+            if PANIC.with(Cell::get) {
+                panic!("finalizer failed");
+            }
+            // Allocates, and keeps alive an object the sweep was about to
+            // free through the new one
+            let holder = Gc::new(Holder { x: self.x.clone() });
+            KEPT.with(|k| k.borrow_mut().push(holder));
+        }
+    }
+
+    gc_rs::collect_garbage();
+
+    drop(Gc::new(Node { x: Gc::new(vec![1, 2, 3]) }));
+    gc_rs::collect_garbage();
+    assert!(stats().live_objects == 2);
+    let kept = KEPT.with(|k| k.borrow_mut().pop().unwrap());
+    assert!(*kept.x == vec![1, 2, 3]);
+    drop(kept);
+    gc_rs::collect_garbage();
+    assert!(stats().live_objects == 0);
+
+    // A panicking finalizer abandons the collection, and the next one
+    // finalizes and frees everything as usual
+    let kept = Gc::new(Holder { x: Gc::new(vec![4]) });
+    drop(Gc::new(Node { x: Gc::new(vec![5]) }));
+    PANIC.with(|p| p.set(true));
+    assert!(catch_unwind(AssertUnwindSafe(gc_rs::collect_garbage)).is_err());
+    assert!(stats().live_objects == 4);
+    PANIC.with(|p| p.set(false));
+    gc_rs::collect_garbage();
+    assert!(stats().live_objects == 4);
+    assert!(*kept.x == vec![4]);
+    let resurrected = KEPT.with(|k| k.borrow_mut().pop().unwrap());
+    assert!(*resurrected.x == vec![5]);
+    drop(kept);
+    drop(resurrected);
+    gc_rs::collect_garbage();
+    assert!(stats().live_objects == 0);
+}
+
+// Keys only hash their name, so the handle's cells don't matter
+#[allow(clippy::mutable_key_type)]
+pub fn result_and_map_keys() {
+    use std::collections::HashMap;
+    use std::hash::{Hash, Hasher};
+
+    // Compared by name, so the handle can be part of a key
+    #[derive(Trace)]
+    struct Key {
+        pub name: String,
+        pub obj: Gc<i32>,
+    }
+
+    impl PartialEq for Key {
+        fn eq(&self, other: &Self) -> bool {
+            self.name == other.name
+        }
+    }
+
+    impl Eq for Key {}
+
+    impl Hash for Key {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.name.hash(state);
+        }
+    }
+
+    #[derive(Trace)]
+    struct Holder {
+        pub result: Result<Gc<i32>, Gc<String>>,
+        pub map: HashMap<Key, Gc<i32>>,
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let mut map = HashMap::new();
+    map.insert(Key { name: "a".to_string(), obj: Gc::new(1) }, Gc::new(2));
+    let holder = Gc::new(Holder { result: Err(Gc::new("failed".to_string())), map });
+
+    // Everything inside is only reachable through the holder
+    assert!(!holder.result.as_ref().unwrap_err().is_root());
+    let (key, value) = holder.map.iter().next().unwrap();
+    assert!(!key.obj.is_root() && !value.is_root());
+    assert!(stats().roots == 1);
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 4);
+    assert!(**holder.result.as_ref().unwrap_err() == "failed");
+    let (key, value) = holder.map.iter().next().unwrap();
+    assert!(*key.obj == 1 && **value == 2);
+
+    drop(holder);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 0);
+}
+
+// Entries only compare and hash their key
+#[allow(clippy::mutable_key_type)]
+pub fn std_collections() {
+    #[cfg(feature = "nightly")]
+    use std::alloc::System;
+    use std::cmp::Ordering;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashSet, LinkedList, VecDeque};
+    use std::hash::{BuildHasherDefault, Hash, Hasher};
+
+    #[derive(Trace)]
+    struct Entry {
+        pub key: i32,
+        pub obj: Gc<i32>,
+    }
+
+    impl PartialEq for Entry {
+        fn eq(&self, other: &Self) -> bool {
+            self.key == other.key
+        }
+    }
+
+    impl Eq for Entry {}
+
+    impl PartialOrd for Entry {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Entry {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.key.cmp(&other.key)
+        }
+    }
+
+    impl Hash for Entry {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.key.hash(state);
+        }
+    }
+
+    fn entries() -> impl Iterator<Item = Entry> {
+        (1..=3).map(|key| Entry { key, obj: Gc::new(key) })
+    }
+
+    // The handles in `container` are kept alive by it, and only it
+    fn check<C: Trace + 'static>(container: C, objects: usize, sum: fn(&C) -> i32) {
+        let holder = Gc::new(container);
+        assert!(stats().roots == 1);
+        GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+        assert!(stats().live_objects == objects + 1);
+        assert!(sum(&holder) == 6);
+        drop(holder);
+        GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+        assert!(stats().live_objects == 0);
+    }
+
+    fn sum<'a>(entries: impl IntoIterator<Item = &'a Entry>) -> i32 {
+        entries.into_iter().map(|entry| *entry.obj).sum()
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    check(entries().collect::<VecDeque<_>>(), 3, |c| sum(c));
+    check(entries().collect::<BTreeSet<_>>(), 3, |c| sum(c));
+    check(entries().collect::<HashSet<_>>(), 3, |c| sum(c));
+    check(entries().collect::<HashSet<_, BuildHasherDefault<DefaultHasher>>>(), 3, |c| sum(c));
+    check(entries().collect::<BinaryHeap<_>>(), 3, |c| sum(c));
+    check(entries().collect::<LinkedList<_>>(), 3, |c| sum(c));
+    check(entries().collect::<Box<[_]>>(), 3, |c| sum(c.iter()));
+    let array: [Entry; 3] = entries().collect::<Vec<_>>().try_into().ok().unwrap();
+    check(array, 3, |c| sum(c));
+    let map = entries().map(|entry| {
+        let value = Gc::new(*entry.obj);
+        (entry, value)
+    });
+    check(map.collect::<BTreeMap<_, _>>(), 6, |c| c.iter().map(|(key, value)| *key.obj + **value).sum::<i32>() / 2);
+
+    // Allocators other than the global one
+    #[cfg(feature = "nightly")]
+    {
+        let mut deque = VecDeque::new_in(System);
+        deque.extend(entries());
+        check(deque, 3, |c| sum(c));
+        let mut vec = Vec::new_in(System);
+        vec.extend(entries());
+        check(vec.into_boxed_slice(), 3, |c| sum(c.iter()));
+    }
+}
+
+pub fn smart_pointers() {
+    use gc_rs::verify_heap;
+    use std::cell::{Cell, RefCell};
+    use std::marker::PhantomData;
+    use std::num::NonZeroU32;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    #[derive(Trace)]
+    struct Leaves {
+        pub name: &'static str,
+        pub timeout: Duration,
+        pub path: PathBuf,
+        pub count: NonZeroU32,
+        pub hits: Cell<u32>,
+        pub marker: PhantomData<*const u8>,
+    }
+
+    // The largest tuple with an impl
+    type Wide = (u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, Gc<i32>);
+
+    #[derive(Trace)]
+    struct Holder {
+        pub boxed: Box<Gc<i32>>,
+        pub dynamic: Box<dyn Trace>,
+        pub maybe: Option<Box<Gc<i32>>>,
+        pub cell: RefCell<Vec<i32>>,
+        pub tuple: (i32, Gc<i32>, String, (Gc<i32>, u8)),
+        pub wide: Wide,
+        pub leaves: Leaves,
+    }
+
+    #[derive(Trace)]
+    struct Shared {
+        pub rc: Rc<Gc<i32>>,
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let holder = Gc::new(Holder {
+        boxed: Box::new(Gc::new(1)),
+        dynamic: Box::new(Gc::new(2)),
+        maybe: Some(Box::new(Gc::new(3))),
+        cell: RefCell::new(vec![4]),
+        tuple: (0, Gc::new(5), String::new(), (Gc::new(6), 0)),
+        wide: (0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, Gc::new(7)),
+        leaves: Leaves {
+            name: "leaves",
+            timeout: Duration::from_secs(1),
+            path: PathBuf::from("/tmp"),
+            count: NonZeroU32::new(1).unwrap(),
+            hits: Cell::new(0),
+            marker: PhantomData,
+        },
+    });
+    assert!(stats().roots == 1);
+
+    // A `RefCell` has nothing to trace, so it can stay borrowed across a
+    // collection
+    {
+        let mut cell = holder.cell.borrow_mut();
+        cell.push(8);
+        GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+        assert!(stats().live_objects == 7);
+    }
+    let sum = **holder.boxed
+        + ***holder.maybe.as_ref().unwrap()
+        + holder.cell.borrow().iter().sum::<i32>()
+        + *holder.tuple.1
+        + *holder.tuple.3 .0
+        + *holder.wide.11;
+    assert!(sum == 1 + 3 + 4 + 8 + 5 + 6 + 7);
+    drop(holder);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 0);
+
+    // An `Rc` shared by two objects and the stack keeps its handle rooted,
+    // and it's never derooted or rooted twice
+    let rc = Rc::new(Gc::new(9));
+    let first = Gc::new(Shared { rc: rc.clone() });
+    let second = Gc::new(Shared { rc: rc.clone() });
+    assert!(rc.is_root());
+    assert!(unsafe { rc.get_roots() } == 1);
+    assert!(verify_heap().is_ok());
+    drop(first);
+    drop(second);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 1);
+    assert!(**rc == 9);
+    drop(rc);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 0);
+}
+
+pub fn dyn_trait() {
+    use gc_rs::gc_dyn;
+
+    trait Shape: Trace {
+        fn area(&self) -> i32;
+        fn grow(&mut self);
+    }
+
+    #[derive(Trace)]
+    struct Square {
+        pub side: i32,
+    }
+
+    #[derive(Trace)]
+    struct Group {
+        pub shapes: Vec<Gc<dyn Shape>>,
+    }
+
+    impl Shape for Square {
+        fn area(&self) -> i32 {
+            self.side * self.side
+        }
+
+        fn grow(&mut self) {
+            self.side += 1;
+        }
+    }
+
+    impl Shape for Group {
+        fn area(&self) -> i32 {
+            self.shapes.iter().map(|shape| shape.area()).sum()
+        }
+
+        fn grow(&mut self) {
+            for shape in &self.shapes {
+                shape.borrow_mut().unwrap().grow();
+            }
+        }
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let square = Gc::new(Square { side: 1 });
+    // A handle coerces like a `Box` with the `nightly` feature
+    #[cfg(feature = "nightly")]
+    let coerced: Gc<dyn Shape> = square.clone();
+    #[cfg(not(feature = "nightly"))]
+    let coerced = gc_dyn!(square.clone(), dyn Shape);
+    let shapes: Vec<Gc<dyn Shape>> = vec![coerced, gc_dyn!(Gc::new(Square { side: 2 }), dyn Shape)];
+    let group = gc_dyn!(Gc::new(Group { shapes }), dyn Shape);
+    assert!(group.area() == 5);
+    assert!(stats().roots == 2);
+
+    // The root moves with the handle, rather than being dropped or added
+    let moved = gc_dyn!(Gc::new(Square { side: 3 }), dyn Shape);
+    assert!(moved.is_root());
+    assert!(unsafe { moved.get_roots() } == 1);
+    assert!(stats().roots == 3);
+    drop(moved);
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 3);
+    group.borrow_mut().unwrap().grow();
+    assert!(group.area() == 4 + 9);
+    assert!(square.side == 2);
+
+    let again = group.clone();
+    assert!(again.ptr_eq(&group));
+    assert!(!again.ptr_eq(&gc_dyn!(square.clone(), dyn Shape)));
+    drop(again);
+    drop(square);
+    drop(group);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 0);
+}
+
+pub fn downcast() {
+    use gc_rs::gc_dyn;
+
+    trait Object: Trace {
+        fn describe(&self) -> String;
+    }
+
+    #[derive(Trace)]
+    struct Int(i64);
+
+    #[derive(Trace)]
+    struct Str(String);
+
+    #[derive(Trace)]
+    struct Slot {
+        pub value: Option<Gc<dyn Object>>,
+    }
+
+    impl Object for Int {
+        fn describe(&self) -> String {
+            self.0.to_string()
+        }
+    }
+
+    impl Object for Str {
+        fn describe(&self) -> String {
+            format!("{:?}", self.0)
+        }
+    }
+
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+
+    let values = vec![
+        gc_dyn!(Gc::new(Int(1)), dyn Object),
+        gc_dyn!(Gc::new(Str("two".to_string())), dyn Object),
+    ];
+    assert!(values.iter().map(|value| value.describe()).collect::<Vec<_>>() == ["1", "\"two\""]);
+    assert!(values[0].is::<Int>() && !values[0].is::<Str>());
+
+    let mut values = values.into_iter();
+    let int = values.next().unwrap().downcast::<Int>().ok().unwrap();
+    assert!(int.0 == 1 && int.is_root());
+    let string = values.next().unwrap().downcast::<Int>().err().unwrap();
+    assert!(string.is_root());
+    let string = string.downcast::<Str>().ok().unwrap();
+    assert!(string.0 == "two");
+    assert!(stats().roots == 2);
+    assert!(unsafe { int.get_roots() } == 1);
+    drop(string);
+
+    // A handle taken out of the heap is still unrooted after the downcast
+    let slot = Gc::new(Slot { value: Some(gc_dyn!(int.clone(), dyn Object)) });
+    drop(int);
+    let value = slot.borrow_mut().unwrap().value.take().unwrap();
+    assert!(!value.is_root());
+    let value = value.downcast::<Str>().err().unwrap();
+    assert!(!value.is_root());
+    let int = value.downcast::<Int>().ok().unwrap();
+    assert!(!int.is_root());
+    assert!(stats().roots == 1);
+    int.root();
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 2);
+    assert!(int.0 == 1);
+
+    drop(slot);
+    drop(int);
+    GC_STATE.with(|st| st.borrow_mut().collect_garbage());
+    assert!(stats().live_objects == 0);
+}
+